@@ -82,7 +82,7 @@ pub const VERSION: RuntimeVersion = RuntimeVersion {
 	// and set impl_version to 0. If only runtime
 	// implementation changes and behavior does not, then leave spec_version as
 	// is and increment impl_version.
-	spec_version: 226,
+	spec_version: 227,
 	impl_version: 0,
 	apis: RUNTIME_API_VERSIONS,
 };
@@ -235,6 +235,8 @@ impl_opaque_keys! {
 
 parameter_types! {
 	pub const DisabledValidatorsThreshold: Perbill = Perbill::from_percent(17);
+	// Covers the bonding duration of `24 * 28` eras of 6 sessions each.
+	pub const SessionHistoryDepth: sp_staking::SessionIndex = 28 * 24 * 6;
 }
 
 impl pallet_session::Trait for Runtime {
@@ -246,6 +248,8 @@ impl pallet_session::Trait for Runtime {
 	type SessionHandler = <SessionKeys as OpaqueKeys>::KeyTypeIdProviders;
 	type Keys = SessionKeys;
 	type DisabledValidatorsThreshold = DisabledValidatorsThreshold;
+	type SessionHistoryDepth = SessionHistoryDepth;
+	type ValidatorHistoryDepth = ();
 }
 
 impl pallet_session::historical::Trait for Runtime {
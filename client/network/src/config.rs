@@ -34,6 +34,7 @@ use crate::service::ExHashT;
 use bitflags::bitflags;
 use sp_consensus::{block_validation::BlockAnnounceValidator, import_queue::ImportQueue};
 use sp_runtime::traits::{Block as BlockT};
+use substrate_prometheus_endpoint::Registry;
 use libp2p::identity::{Keypair, ed25519};
 use libp2p::wasm_ext;
 use libp2p::{PeerId, Multiaddr, multiaddr};
@@ -90,6 +91,9 @@ pub struct Params<B: BlockT, H: ExHashT> {
 
 	/// Type to check incoming block announcements.
 	pub block_announce_validator: Box<dyn BlockAnnounceValidator<B> + Send>,
+
+	/// Registry for recording Prometheus metrics, if any.
+	pub metrics_registry: Option<Registry>,
 }
 
 bitflags! {
@@ -16,7 +16,7 @@
 
 use crate::{DiscoveryNetBehaviour, config::ProtocolId};
 use crate::protocol::message::generic::{Message as GenericMessage, ConsensusMessage};
-use crate::protocol::generic_proto::handler::{NotifsHandlerProto, NotifsHandlerOut, NotifsHandlerIn};
+use crate::protocol::generic_proto::handler::{NotifsHandlerProto, NotifsHandlerOut, NotifsHandlerIn, NotifsInMetrics};
 use crate::protocol::generic_proto::upgrade::RegisteredProtocol;
 
 use bytes::BytesMut;
@@ -32,6 +32,7 @@ use sp_runtime::ConsensusEngineId;
 use std::{borrow::Cow, collections::hash_map::Entry, cmp};
 use std::{error, mem, pin::Pin, str, time::Duration};
 use std::task::{Context, Poll};
+use substrate_prometheus_endpoint::{PrometheusError, Registry};
 use wasm_timer::Instant;
 
 /// Network behaviour that handles opening substreams for custom protocols with other nodes.
@@ -86,6 +87,9 @@ pub struct GenericProto {
 	/// Notification protocols. Entries are only ever added and not removed.
 	notif_protocols: Vec<(Cow<'static, [u8]>, ConsensusEngineId, Vec<u8>)>,
 
+	/// Prometheus metrics of the inbound notifications, if enabled.
+	in_metrics: Option<NotifsInMetrics>,
+
 	/// Receiver for instructions about who to connect to or disconnect from.
 	peerset: sc_peerset::Peerset,
 
@@ -256,6 +260,7 @@ impl GenericProto {
 		GenericProto {
 			legacy_protocol,
 			notif_protocols: Vec::new(),
+			in_metrics: None,
 			peerset,
 			peers: FnvHashMap::default(),
 			incoming: SmallVec::new(),
@@ -274,7 +279,25 @@ impl GenericProto {
 		engine_id: ConsensusEngineId,
 		handshake_msg: impl Into<Vec<u8>>
 	) {
-		self.notif_protocols.push((protocol_name.into(), engine_id, handshake_msg.into()));
+		let protocol_name = protocol_name.into();
+		if let Some(in_metrics) = self.in_metrics.as_mut() {
+			in_metrics.add_protocol(protocol_name.clone());
+		}
+		self.notif_protocols.push((protocol_name, engine_id, handshake_msg.into()));
+	}
+
+	/// Registers Prometheus metrics about the inbound notifications of each protocol in the
+	/// given registry.
+	///
+	/// Just like for `register_notif_protocol`, only connections opened afterwards report
+	/// metrics.
+	pub fn register_metrics(&mut self, registry: &Registry) -> Result<(), PrometheusError> {
+		let mut in_metrics = NotifsInMetrics::register(registry)?;
+		for (protocol_name, _, _) in &self.notif_protocols {
+			in_metrics.add_protocol(protocol_name.clone());
+		}
+		self.in_metrics = Some(in_metrics);
+		Ok(())
 	}
 
 	/// Returns the list of all the peers we have an open channel to.
@@ -691,7 +714,11 @@ impl NetworkBehaviour for GenericProto {
 	type OutEvent = GenericProtoOut;
 
 	fn new_handler(&mut self) -> Self::ProtocolsHandler {
-		NotifsHandlerProto::new(self.legacy_protocol.clone(), self.notif_protocols.clone())
+		NotifsHandlerProto::new(
+			self.legacy_protocol.clone(),
+			self.notif_protocols.clone(),
+			self.in_metrics.as_ref(),
+		)
 	}
 
 	fn addresses_of_peer(&mut self, _: &PeerId) -> Vec<Multiaddr> {
@@ -47,9 +47,9 @@
 
 use crate::protocol::generic_proto::{
 	handler::legacy::{LegacyProtoHandler, LegacyProtoHandlerProto, LegacyProtoHandlerIn, LegacyProtoHandlerOut},
-	handler::notif_in::{NotifsInHandlerProto, NotifsInHandler, NotifsInHandlerIn, NotifsInHandlerOut},
+	handler::notif_in::{NotifsInHandlerProto, NotifsInHandler, NotifsInHandlerIn, NotifsInHandlerOut, NotifsInMetrics},
 	handler::notif_out::{NotifsOutHandlerProto, NotifsOutHandler, NotifsOutHandlerIn, NotifsOutHandlerOut},
-	upgrade::{NotificationsIn, NotificationsOut, NotificationsHandshakeError, RegisteredProtocol, UpgradeCollec},
+	upgrade::{DEFAULT_MAX_HANDSHAKE_SIZE, NotificationsIn, NotificationsOut, NotificationsHandshakeError, RegisteredProtocol, UpgradeCollec},
 };
 use crate::protocol::message::generic::{Message as GenericMessage, ConsensusMessage};
 
@@ -67,7 +67,13 @@ use libp2p::swarm::{
 };
 use log::error;
 use sp_runtime::ConsensusEngineId;
-use std::{borrow::Cow, error, io, task::{Context, Poll}};
+use std::{borrow::Cow, error, io, task::{Context, Poll}, time::Duration};
+
+/// Maximum allowed size, in bytes, of a notification received on a notifications substream.
+const MAX_NOTIFICATION_SIZE: u64 = 16 * 1024 * 1024;
+/// Maximum duration for which inbound notifications substreams wait for being accepted or
+/// refused before being automatically refused.
+const ACCEPT_REFUSE_TIMEOUT: Duration = Duration::from_secs(20);
 
 /// Implements the `IntoProtocolsHandler` trait of libp2p.
 ///
@@ -106,7 +112,7 @@ pub struct NotifsHandler {
 	/// If we receive inbound substream requests while in initialization mode,
 	/// we push the corresponding index here and process them when the handler
 	/// gets enabled/disabled.
-	pending_in: Vec<usize>,
+	pending_in: Vec<(usize, u64)>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -238,11 +244,34 @@ pub enum NotifsHandlerOut {
 
 impl NotifsHandlerProto {
 	/// Builds a new handler.
-	pub fn new(legacy: RegisteredProtocol, list: impl Into<Vec<(Cow<'static, [u8]>, ConsensusEngineId, Vec<u8>)>>) -> Self {
+	///
+	/// If `in_metrics` is `Some`, the inbound traffic of the protocols it contains is reported
+	/// to it.
+	pub fn new(
+		legacy: RegisteredProtocol,
+		list: impl Into<Vec<(Cow<'static, [u8]>, ConsensusEngineId, Vec<u8>)>>,
+		in_metrics: Option<&NotifsInMetrics>,
+	) -> Self {
 		let list = list.into();
 
 		NotifsHandlerProto {
-			in_handlers: list.clone().into_iter().map(|(p, e, _)| (NotifsInHandlerProto::new(p), e)).collect(),
+			in_handlers: list.clone()
+				.into_iter()
+				.map(|(p, e, _)| {
+					let metrics = in_metrics.and_then(|m| m.protocol(&p));
+					let mut proto = NotifsInHandlerProto::new(
+						p,
+						Vec::new(),
+						DEFAULT_MAX_HANDSHAKE_SIZE,
+						MAX_NOTIFICATION_SIZE,
+						ACCEPT_REFUSE_TIMEOUT,
+					);
+					if let Some(metrics) = metrics {
+						proto = proto.with_metrics(metrics);
+					}
+					(proto, e)
+				})
+				.collect(),
 			out_handlers: list.clone().into_iter().map(|(p, e, _)| (NotifsOutHandlerProto::new(p), e)).collect(),
 			legacy: LegacyProtoHandlerProto::new(legacy),
 		}
@@ -309,8 +338,9 @@ impl ProtocolsHandler for NotifsHandler {
 						initial_message: vec![]
 					});
 				}
-				for num in self.pending_in.drain(..) {
-					self.in_handlers[num].0.inject_event(NotifsInHandlerIn::Accept(vec![]));
+				for (num, generation) in self.pending_in.drain(..) {
+					let event = NotifsInHandlerIn::AcceptDefault { generation };
+					self.in_handlers[num].0.inject_event(event);
 				}
 			},
 			NotifsHandlerIn::Disable => {
@@ -323,8 +353,8 @@ impl ProtocolsHandler for NotifsHandler {
 					}
 				}
 				self.enabled = EnabledState::Disabled;
-				for num in self.pending_in.drain(..) {
-					self.in_handlers[num].0.inject_event(NotifsInHandlerIn::Refuse);
+				for (num, generation) in self.pending_in.drain(..) {
+					self.in_handlers[num].0.inject_event(NotifsInHandlerIn::Refuse { generation });
 				}
 			},
 			NotifsHandlerIn::SendLegacy { message } =>
@@ -438,16 +468,58 @@ impl ProtocolsHandler for NotifsHandler {
 					ProtocolsHandlerEvent::OutboundSubstreamRequest { .. } =>
 						error!("Incoming substream handler tried to open a substream"),
 					ProtocolsHandlerEvent::Close(err) => void::unreachable(err),
-					ProtocolsHandlerEvent::Custom(NotifsInHandlerOut::OpenRequest(_)) =>
+					ProtocolsHandlerEvent::Custom(NotifsInHandlerOut::OpenRequest { generation, .. }) =>
 						match self.enabled {
-							EnabledState::Initial => self.pending_in.push(handler_num),
+							EnabledState::Initial => self.pending_in.push((handler_num, generation)),
 							EnabledState::Enabled =>
-								handler.inject_event(NotifsInHandlerIn::Accept(vec![])),
+								handler.inject_event(NotifsInHandlerIn::AcceptDefault {
+									generation,
+								}),
 							EnabledState::Disabled =>
-								handler.inject_event(NotifsInHandlerIn::Refuse),
+								handler.inject_event(NotifsInHandlerIn::Refuse { generation }),
 						},
-					ProtocolsHandlerEvent::Custom(NotifsInHandlerOut::Closed) => {},
-					ProtocolsHandlerEvent::Custom(NotifsInHandlerOut::Notif(message)) => {
+					ProtocolsHandlerEvent::Custom(NotifsInHandlerOut::Closed { .. }) => {},
+					ProtocolsHandlerEvent::Custom(NotifsInHandlerOut::RefusedByTimeout { .. }) => {},
+					// Statistics and bandwidth reporting, flapping and duplicates detection, and
+					// batching aren't enabled for the handlers we build.
+					ProtocolsHandlerEvent::Custom(NotifsInHandlerOut::Stats(_)) => {},
+					ProtocolsHandlerEvent::Custom(NotifsInHandlerOut::BytesReceivedReport { .. }) => {},
+					ProtocolsHandlerEvent::Custom(NotifsInHandlerOut::Flapping { .. }) => {},
+					ProtocolsHandlerEvent::Custom(NotifsInHandlerOut::DuplicateSubstream { .. }) => {},
+					// We never ask the handlers to shut down.
+					ProtocolsHandlerEvent::Custom(NotifsInHandlerOut::ShutdownComplete { .. }) => {},
+					ProtocolsHandlerEvent::Custom(NotifsInHandlerOut::NotifBatch { .. }) => {
+						error!(target: "sub-libp2p", "Unexpected batch of notifications");
+					},
+					ProtocolsHandlerEvent::Custom(NotifsInHandlerOut::ProtocolViolation { error, .. }) =>
+						return Poll::Ready(ProtocolsHandlerEvent::Custom(
+							NotifsHandlerOut::ProtocolError {
+								is_severe: true,
+								error: Box::new(error),
+							}
+						)),
+					ProtocolsHandlerEvent::Custom(NotifsInHandlerOut::Error { error, .. }) =>
+						return Poll::Ready(ProtocolsHandlerEvent::Custom(
+							NotifsHandlerOut::ProtocolError {
+								is_severe: false,
+								error: Box::new(error),
+							}
+						)),
+					ProtocolsHandlerEvent::Custom(NotifsInHandlerOut::UpgradeError(err)) =>
+						return Poll::Ready(ProtocolsHandlerEvent::Custom(
+							NotifsHandlerOut::ProtocolError {
+								is_severe: false,
+								error: Box::new(err),
+							}
+						)),
+					ProtocolsHandlerEvent::Custom(NotifsInHandlerOut::RateLimitExceeded { .. }) =>
+						return Poll::Ready(ProtocolsHandlerEvent::Custom(
+							NotifsHandlerOut::ProtocolError {
+								is_severe: true,
+								error: "Notifications rate limit exceeded".to_string().into(),
+							}
+						)),
+					ProtocolsHandlerEvent::Custom(NotifsInHandlerOut::Notif { message, .. }) => {
 						// Note that right now the legacy substream has precedence over
 						// everything. If it is not open, then we consider that nothing is open.
 						if self.legacy.is_open() {
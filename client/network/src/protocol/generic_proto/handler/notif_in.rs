@@ -17,13 +17,18 @@
 //! Implementations of the `IntoProtocolsHandler` and `ProtocolsHandler` traits for ingoing
 //! substreams for a single gossiping protocol.
 //!
-//! > **Note**: Each instance corresponds to a single protocol. In order to support multiple
-//! >			protocols, you need to create multiple instances and group them.
+//! > **Note**: Each [`NotifsInHandler`] corresponds to a single protocol. In order to support
+//! >			multiple protocols with a single handler, use a [`NotifsInMultiHandler`], which
+//! >			groups several of them.
 //!
 
-use crate::protocol::generic_proto::upgrade::{NotificationsIn, NotificationsInSubstream};
+use crate::protocol::generic_proto::upgrade::{
+	CompressionConfig, NotificationsIn, NotificationsInSubstream, NotificationsInError,
+	NotificationsVersion, UpgradeCollec,
+};
 use bytes::BytesMut;
 use futures::prelude::*;
+use futures_timer::Delay;
 use libp2p::core::{ConnectedPoint, PeerId};
 use libp2p::core::upgrade::{DeniedUpgrade, InboundUpgrade, OutboundUpgrade};
 use libp2p::swarm::{
@@ -34,9 +39,23 @@ use libp2p::swarm::{
 	SubstreamProtocol,
 	NegotiatedSubstream,
 };
-use log::{error, warn};
-use smallvec::SmallVec;
-use std::{borrow::Cow, fmt, pin::Pin, str, task::{Context, Poll}};
+use fnv::FnvHashMap;
+use log::{debug, error, warn};
+use std::{borrow::Cow, cmp, collections::VecDeque, error, fmt, io, mem, pin::Pin, str, task::{Context, Poll}};
+use std::{iter::FromIterator, sync::Arc, task::Waker, time::Duration};
+use substrate_prometheus_endpoint::{register, Counter, CounterVec, Opts, PrometheusError, Registry, U64};
+use wasm_timer::Instant;
+
+/// Maximum number of refused substreams on which we are sending the reason of the refusal at
+/// the same time. Further substreams are refused without a reason.
+const MAX_REFUSING_SUBSTREAMS: usize = 4;
+/// Maximum number of bytes of the protocol name printed by the `Debug` implementation of the
+/// handler.
+const MAX_DEBUG_PROTOCOL_NAME_LEN: usize = 64;
+/// Default maximum duration of a shutdown. See [`NotifsInHandlerProto::with_shutdown_timeout`].
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+/// Minimum interval between two periodic reports. Shorter intervals are raised to this value.
+const MIN_REPORT_INTERVAL: Duration = Duration::from_millis(10);
 
 /// Implements the `IntoProtocolsHandler` trait of libp2p.
 ///
@@ -46,74 +65,766 @@ use std::{borrow::Cow, fmt, pin::Pin, str, task::{Context, Poll}};
 pub struct NotifsInHandlerProto {
 	/// Configuration for the protocol upgrade to negotiate.
 	in_protocol: NotificationsIn,
+
+	/// Handshake to send back when accepting a substream with `AcceptDefault`.
+	handshake: Vec<u8>,
+
+	/// Maximum duration to wait for an `Accept` or `Refuse` after emitting an `OpenRequest`.
+	accept_refuse_timeout: Duration,
+
+	/// Limits to enforce on the received notifications, if any.
+	rate_limit: Option<NotifsInRateLimit>,
+
+	/// How long to keep the connection alive after the substream has closed.
+	keep_alive_grace: Duration,
+
+	/// If `Some`, a `Stats` event is emitted at this interval.
+	stats_interval: Option<Duration>,
+
+	/// If `Some`, a `BytesReceivedReport` event is emitted at this interval.
+	bandwidth_report_interval: Option<Duration>,
+
+	/// Prometheus metrics of the protocol, if enabled.
+	metrics: Option<Arc<NotifsInProtocolMetrics>>,
+
+	/// If `Some`, notifications are delivered in batches.
+	batching: Option<NotifsInBatching>,
+
+	/// If `Some`, report remotes that open substreams too often.
+	flapping: Option<NotifsInFlapping>,
+
+	/// If `Some`, maximum number of notifications to read in a row before yielding.
+	poll_budget: Option<usize>,
+
+	/// If `Some`, maximum number of notifications delivered and not acknowledged yet.
+	ack_window: Option<usize>,
+
+	/// If `Some`, close the substreams with keep-alive pings on which nothing is received for
+	/// this long.
+	idle_timeout: Option<Duration>,
+
+	/// What to do when the remote opens a substream while another one is already there.
+	duplicate_policy: NotifsInDuplicatePolicy,
+
+	/// Maximum duration of a shutdown started with `Shutdown`.
+	shutdown_timeout: Duration,
+}
+
+/// Error reported by a [`NotifsInHandler`] through [`NotifsInHandlerOut::Error`].
+#[derive(Debug, derive_more::Display)]
+pub enum NotifsInError {
+	/// The remote has sent data that can't be decoded.
+	#[display(fmt = "Failed to decode notification: {}", _0)]
+	Codec(io::Error),
+
+	/// I/O error on the substream.
+	#[display(fmt = "I/O error on substream: {}", _0)]
+	Io(io::Error),
+}
+
+impl NotifsInError {
+	/// Returns the kind of the underlying I/O error.
+	pub fn io_error_kind(&self) -> io::ErrorKind {
+		match self {
+			NotifsInError::Codec(err) | NotifsInError::Io(err) => err.kind(),
+		}
+	}
+}
+
+impl From<io::Error> for NotifsInError {
+	fn from(err: io::Error) -> Self {
+		// Framing errors are reported by the codec as `InvalidData`.
+		if err.kind() == io::ErrorKind::InvalidData {
+			NotifsInError::Codec(err)
+		} else {
+			NotifsInError::Io(err)
+		}
+	}
+}
+
+impl error::Error for NotifsInError {
+	fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+		match self {
+			NotifsInError::Codec(err) | NotifsInError::Io(err) => Some(err),
+		}
+	}
+}
+
+/// What a [`NotifsInHandler`] does when the remote opens a substream while the previous one is
+/// still open or waiting for an answer to its `OpenRequest`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum NotifsInDuplicatePolicy {
+	/// Drop the new substream. The previous one isn't affected.
+	RejectNew,
+	/// Close the previous substream and emit `Closed` for it, then handle the new substream
+	/// normally and emit an `OpenRequest`. Useful for remotes that re-open their substream
+	/// after having half-closed it.
+	ReplaceOld,
+	/// Drop the new substream and emit a `DuplicateSubstream` event. The previous one isn't
+	/// affected.
+	RejectAndReport,
+}
+
+/// Limits on the size of the batches of notifications delivered by a [`NotifsInHandler`].
+///
+/// Batches only ever contain notifications that have already been received. The handler never
+/// waits for a batch to be full.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct NotifsInBatching {
+	/// Maximum number of notifications in a batch. A value of 0 is treated as 1.
+	pub max_messages: usize,
+	/// A batch is closed as soon as the total size of its notifications reaches this number of
+	/// bytes.
+	pub max_bytes: usize,
+}
+
+/// Prometheus metrics about the inbound notifications of all the protocols.
+///
+/// Registered once per registry. The metrics of each protocol are then shared between all the
+/// handlers of that protocol, so that the number of time series doesn't depend on the number of
+/// peers.
+pub struct NotifsInMetrics {
+	notifications_received: CounterVec<U64>,
+	bytes_received: CounterVec<U64>,
+	open_requests_refused: CounterVec<U64>,
+	/// Metrics of each protocol added with `add_protocol`.
+	protocols: FnvHashMap<Cow<'static, [u8]>, Arc<NotifsInProtocolMetrics>>,
+}
+
+/// Prometheus metrics about the inbound notifications of a single protocol.
+pub struct NotifsInProtocolMetrics {
+	notifications_received: Counter<U64>,
+	bytes_received: Counter<U64>,
+	open_requests_refused: Counter<U64>,
+}
+
+/// Configuration of the detection of a remote rapidly opening and closing substreams.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NotifsInFlapping {
+	/// Length of the sliding window in which substream openings are counted.
+	pub window: Duration,
+	/// The remote is considered as flapping if it opens more than this number of substreams
+	/// within `window`.
+	pub max_opens: usize,
+}
+
+/// Limits on the rate at which notifications can be received on an inbound substream.
+///
+/// Both limits are also the maximum burst allowed: a remote that has been quiet for a while can
+/// send up to `messages_per_sec` notifications and `bytes_per_sec` bytes at once.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NotifsInRateLimit {
+	/// Maximum number of notifications per second. A value of 0 is treated as 1.
+	pub messages_per_sec: u32,
+	/// Maximum number of bytes per second. A value of 0 is treated as 1.
+	pub bytes_per_sec: u64,
+	/// If the remote continuously sends notifications at or above the limits for longer than
+	/// this, it is considered as a protocol violation and the substream is closed.
+	pub max_saturation: Duration,
 }
 
 /// The actual handler once the connection has been established.
-pub struct NotifsInHandler {
+///
+/// Generic over the substream type for testing purposes. Only `NegotiatedSubstream` implements
+/// the `ProtocolsHandler` trait.
+pub struct NotifsInHandler<TSubstream = NegotiatedSubstream> {
 	/// Configuration for the protocol upgrade to negotiate for inbound substreams.
 	in_protocol: NotificationsIn,
 
-	/// Substream that is open with the remote.
-	substream: Option<NotificationsInSubstream<NegotiatedSubstream>>,
+	/// State of the inbound substream.
+	state: State<TSubstream>,
 
-	/// If the substream is opened and closed rapidly, we can emit several `OpenRequest` and
-	/// `Closed` messages in a row without the handler having time to respond with `Accept` or
-	/// `Refuse`.
-	///
-	/// In order to keep the state consistent, we increment this variable every time an
-	/// `OpenRequest` is emitted and decrement it every time an `Accept` or `Refuse` is received.
-	pending_accept_refuses: usize,
+	/// Handshake to send back when accepting a substream with `AcceptDefault`. Updated by
+	/// `Accept` and `UpdateHandshake`.
+	handshake: Vec<u8>,
+
+	/// Generation to assign to the next substream opened by the remote.
+	next_generation: u64,
+
+	/// Substreams that we are in the process of closing following a `Close` message, and their
+	/// generation. Not part of `state`, as the remote can open a new substream in the meanwhile,
+	/// which can itself be closed before the previous one is done.
+	closing_substreams: VecDeque<(NotificationsInSubstream<TSubstream>, u64)>,
+
+	/// Substreams that have been refused with `RefuseWithReason`, and on which we are sending
+	/// the reason before closing them.
+	refusing_substreams: Vec<NotificationsInSubstream<TSubstream>>,
+
+	/// If true, we don't read from `substream` until a `Resume` is received. Reset every time a
+	/// new substream is opened.
+	paused: bool,
+
+	/// Rate limiting of the notifications, if enabled. Reset every time a new substream is
+	/// opened.
+	rate_limit: Option<TokenBucket>,
+
+	/// If `Some`, we have stopped reading from the substream because of `rate_limit`, and will
+	/// try again when this fires.
+	rate_limit_delay: Option<Delay>,
+
+	/// How long to keep the connection alive after the substream has closed.
+	keep_alive_grace: Duration,
+
+	/// If no substream is open, we keep the connection alive until this moment. Updated every
+	/// time the substream closes.
+	keep_alive_until: Option<Instant>,
+
+	/// Maximum duration to wait for an `Accept` or `Refuse` after emitting an `OpenRequest`.
+	accept_refuse_timeout: Duration,
+
+	/// Statistics about the substreams, over the lifetime of the connection.
+	stats: NotifsInStats,
+
+	/// If `Some`, emit a `Stats` event every time this fires, then reset it to the interval.
+	stats_timer: Option<(Delay, Duration)>,
+
+	/// If `Some`, emit a `BytesReceivedReport` event every time this fires, then reset it to the
+	/// interval.
+	bandwidth_timer: Option<(Delay, Duration)>,
+
+	/// Number of bytes of notifications received since the last `BytesReceivedReport`.
+	bytes_since_report: u64,
+
+	/// Prometheus metrics of the protocol, if enabled.
+	metrics: Option<Arc<NotifsInProtocolMetrics>>,
+
+	/// If `Some`, notifications are delivered in batches.
+	batching: Option<NotifsInBatching>,
+
+	/// Detection of remotes rapidly opening and closing substreams, if enabled.
+	flapping: Option<FlappingDetector>,
+
+	/// If `Some`, maximum number of notifications to read in a row before yielding.
+	poll_budget: Option<usize>,
+
+	/// Number of notifications read since the substream was last pending, or since we last
+	/// yielded because of `poll_budget`.
+	notifs_in_a_row: usize,
+
+	/// If `Some`, maximum number of notifications delivered and not acknowledged yet.
+	ack_window: Option<usize>,
+
+	/// Number of notifications of the current substream delivered to the outside and not
+	/// acknowledged yet. Only relevant if `ack_window` is `Some`.
+	unacked: usize,
+
+	/// If `Some`, close the substream if it has keep-alive pings and nothing is received for
+	/// this long.
+	idle_timeout: Option<Duration>,
+
+	/// Fires when the substream is considered idle. Reset every time the substream is read.
+	idle_timer: Option<Delay>,
+
+	/// What to do when the remote opens a substream while another one is already there.
+	duplicate_policy: NotifsInDuplicatePolicy,
+
+	/// Maximum duration of a shutdown started with `Shutdown`.
+	shutdown_timeout: Duration,
+
+	/// If `Some`, a shutdown is in progress and the substreams still being closed are dropped
+	/// when this fires.
+	shutdown_deadline: Option<Delay>,
+
+	/// If true, the shutdown is over. No substream is accepted anymore, and the connection is no
+	/// longer kept alive.
+	shutdown_complete: bool,
+
+	/// Substreams that were open or waiting for an answer when the shutdown started, and that
+	/// we are closing.
+	draining: Vec<NotificationsInSubstream<TSubstream>>,
 
 	/// Queue of events to send to the outside.
 	///
 	/// This queue is only ever modified to insert elements at the back, or remove the first
 	/// element.
-	events_queue: SmallVec<[ProtocolsHandlerEvent<DeniedUpgrade, (), NotifsInHandlerOut, void::Void>; 16]>,
+	events_queue: VecDeque<ProtocolsHandlerEvent<DeniedUpgrade, (), NotifsInHandlerOut, void::Void>>,
+
+	/// Waker of the task that last polled the handler, if any. Woken up when something happens
+	/// outside of `poll`, such as a new substream or a message from the outside.
+	waker: Option<Waker>,
+}
+
+/// Statistics about the notifications received by a [`NotifsInHandler`].
+///
+/// The counters cover all the substreams that have been opened over the lifetime of the
+/// connection, and saturate instead of overflowing.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NotifsInStats {
+	/// Number of notifications received.
+	pub notifications_received: u64,
+	/// Total size in bytes of the notifications received.
+	pub bytes_received: u64,
+	/// When the last notification has been received, if any.
+	pub last_notification: Option<Instant>,
+	/// Number of `OpenRequest`s that have been answered with `Accept`.
+	pub open_requests_accepted: u64,
+	/// Number of `OpenRequest`s that have been answered with `Refuse` or `Close`, or that have
+	/// timed out.
+	pub open_requests_refused: u64,
+}
+
+/// State of the inbound substream of a [`NotifsInHandler`].
+///
+/// Every substream is assigned a generation, which is included in the events concerning it and
+/// in the answers to its `OpenRequest`. If the substream is opened and closed rapidly, the
+/// outside can answer an `OpenRequest` after the substream it concerns has been closed, and
+/// possibly after a new one has been opened. Comparing generations lets us ignore such
+/// obsolete answers.
+///
+/// Transitions:
+///
+/// - A new substream moves from `Closed` to `PendingAcceptRefuse` with a new generation, and
+///   emits an `OpenRequest`. A new substream in any other state is dropped.
+/// - In `PendingAcceptRefuse`, an `Accept` or `Refuse` for the current generation moves to
+///   `Open` or `Closed`. Answers concerning previous generations are ignored in every state.
+/// - A `Close` in `PendingAcceptRefuse` counts as a `Refuse` and moves to `Closed`. In `Open`,
+///   it moves to `Closed` as well.
+/// - The substream closing, whether by the remote, because of an error, or because the outside
+///   didn't answer in time, moves to `Closed`.
+enum State<TSubstream> {
+	/// No substream is open.
+	Closed,
+
+	/// An `OpenRequest` has been emitted for the substream, and we are waiting for the answer.
+	PendingAcceptRefuse {
+		/// The substream, which we don't read from before it is accepted.
+		substream: NotificationsInSubstream<TSubstream>,
+		/// Generation of the substream.
+		generation: u64,
+		/// Automatically refuse the substream when this fires.
+		deadline: Delay,
+	},
+
+	/// The substream has been accepted.
+	Open {
+		/// The substream, which we read notifications from.
+		substream: NotificationsInSubstream<TSubstream>,
+		/// Generation of the substream.
+		generation: u64,
+	},
+
+	/// Temporary state while transitioning. Should never be observed.
+	Poisoned,
+}
+
+/// State of the inbound substream of a `NotifsInHandler`, as returned by
+/// [`NotifsInHandler::state`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum NotifsInState {
+	/// No substream is open.
+	Closed,
+
+	/// The remote has opened a substream and an `OpenRequest` has been emitted. We are waiting
+	/// for an `Accept` or a `Refuse`.
+	PendingAcceptRefuse,
+
+	/// The substream has been accepted, and notifications are being received.
+	Open,
+
+	/// The substream has been closed following a `Close`, and is being flushed. No more
+	/// notifications are received. A `Closed` event will be emitted once it is done.
+	Closing,
 }
 
 /// Event that can be received by a `NotifsInHandler`.
 #[derive(Debug)]
 pub enum NotifsInHandlerIn {
-	/// Can be sent back as a response to an `OpenRequest`. Contains the status message to send
-	/// to the remote.
+	/// Can be sent back as a response to an `OpenRequest`.
 	///
 	/// After sending this to the handler, the substream is now considered open and `Notif` events
-	/// can be received.
-	Accept(Vec<u8>),
+	/// can be received. Ignored if the substream of this generation has already been closed.
+	///
+	/// The handshake also replaces the one sent by `AcceptDefault`.
+	Accept {
+		/// Generation of the substream, as indicated in the `OpenRequest`.
+		generation: u64,
+		/// Status message to send to the remote.
+		handshake: Vec<u8>,
+	},
 
-	/// Can be sent back as a response to an `OpenRequest`.
-	Refuse,
+	/// Same as `Accept`, but sends to the remote the handshake passed to
+	/// [`NotifsInHandlerProto::new`], or the one of the latest `Accept` or `UpdateHandshake`.
+	AcceptDefault {
+		/// Generation of the substream, as indicated in the `OpenRequest`.
+		generation: u64,
+	},
+
+	/// Replaces the handshake sent by `AcceptDefault`, for example because the best block has
+	/// changed. Doesn't affect the substreams that have already been accepted.
+	UpdateHandshake {
+		/// New status message to send to the remote.
+		handshake: Vec<u8>,
+	},
+
+	/// Can be sent back as a response to an `OpenRequest`. Ignored if the substream of this
+	/// generation has already been closed.
+	Refuse {
+		/// Generation of the substream, as indicated in the `OpenRequest`.
+		generation: u64,
+	},
+
+	/// Same as `Refuse`, but also sends to the remote a short message explaining why, such as
+	/// "peer slots full". The reason is truncated to `MAX_REFUSAL_REASON_SIZE` bytes.
+	RefuseWithReason {
+		/// Generation of the substream, as indicated in the `OpenRequest`.
+		generation: u64,
+		/// Reason of the refusal.
+		reason: Vec<u8>,
+	},
+
+	/// Stops reading notifications from the substream, without closing it. The remote is then
+	/// back-pressured until a `Resume` is sent.
+	///
+	/// > **Note**: The handshake sent with `Accept` is still flushed while paused.
+	///
+	/// Has no effect if no substream is open.
+	Pause,
+
+	/// Resumes reading notifications after a `Pause`. No notification is lost in the process.
+	///
+	/// Has no effect if the handler isn't paused.
+	Resume,
+
+	/// Closes the substream. If the handshake of an `Accept` is still being sent, it is flushed
+	/// first. A `Closed` event is emitted once the substream has been closed.
+	///
+	/// If an `OpenRequest` hasn't been answered yet, this counts as a `Refuse` for it.
+	///
+	/// Has no effect if no substream is open.
+	Close,
+
+	/// Stops reading notifications and properly closes the substreams, after having flushed the
+	/// handshake of an `Accept` if necessary. A pending `OpenRequest` counts as refused. Emits
+	/// `ShutdownComplete` once done, or once the timeout set with
+	/// [`NotifsInHandlerProto::with_shutdown_timeout`] is reached.
+	///
+	/// If a substream was open, `Closed` is emitted for it right away. Afterwards, the
+	/// substreams opened by the remote are dropped and the connection is no longer kept alive.
+	Shutdown,
+
+	/// Acknowledges one of the notifications of the given generation. Only meaningful if the
+	/// acknowledgement mode has been enabled with [`NotifsInHandlerProto::with_ack_window`].
+	///
+	/// `Closed` is emitted regardless of the acknowledgements that are outstanding. Once a
+	/// substream has been closed or replaced, the acknowledgements concerning it are ignored.
+	NotifAck {
+		/// Generation of the substream the notification has been received on.
+		generation: u64,
+	},
+}
+
+/// Reason why a substream has been closed, as reported by [`NotifsInHandlerOut::Closed`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum NotifsInClosedReason {
+	/// The remote has closed the substream.
+	Remote,
+	/// The substream has been closed following a `Close`.
+	Local,
+	/// The remote has opened a new substream, which replaces this one. See
+	/// [`NotifsInDuplicatePolicy::ReplaceOld`].
+	Replaced,
+	/// The handler is shutting down following a `Shutdown`.
+	Shutdown,
+	/// The remote sends keep-alive pings, but nothing has been received within the timeout set
+	/// with [`NotifsInHandlerProto::with_idle_timeout`].
+	IdleTimeout,
 }
 
 /// Event that can be emitted by a `NotifsInHandler`.
 #[derive(Debug)]
 pub enum NotifsInHandlerOut {
-	/// The remote wants to open a substream. Contains the initial message sent by the remote
-	/// when the substream has been opened.
+	/// The remote wants to open a substream.
 	///
-	/// Every time this event is emitted, a corresponding `Accepted` or `Refused` **must** be sent
-	/// back even if a `Closed` is received.
-	OpenRequest(Vec<u8>),
+	/// The outside is expected to answer with an `Accept` or a `Refuse` carrying the same
+	/// generation. Answers that arrive after the substream has been closed are ignored.
+	OpenRequest {
+		/// Initial message sent by the remote when the substream has been opened.
+		handshake: Vec<u8>,
+		/// Name of the protocol that has been negotiated. Either the main protocol name or one
+		/// of the fallback names.
+		protocol_name: Cow<'static, [u8]>,
+		/// Version of the protocol that has been negotiated, or `None` if the protocol has been
+		/// registered without versions. Tells which features the remote supports.
+		version: Option<u32>,
+		/// Generation of the substream. Strictly greater than the one of all the previous
+		/// substreams of this handler.
+		generation: u64,
+	},
 
-	/// The notifications substream has been closed by the remote. In order to avoid race
-	/// conditions, this does **not** cancel any previously-sent `OpenRequest`.
-	Closed,
+	/// No `Accept` or `Refuse` has been received in time after an `OpenRequest`. The substream
+	/// has been refused and closed.
+	RefusedByTimeout {
+		/// Generation of the substream that has been refused.
+		generation: u64,
+	},
+
+	/// The notifications substream has been cleanly closed by the remote, following a `Close`,
+	/// or because it was idle.
+	Closed {
+		/// Generation of the substream that has been closed.
+		generation: u64,
+		/// Why the substream has been closed.
+		reason: NotifsInClosedReason,
+	},
 
 	/// Received a message on the notifications substream.
 	///
-	/// Can only happen after an `Accept` and before a `Closed`.
-	Notif(BytesMut),
+	/// Can only happen after an `Accept` and before a `Closed` of the same generation.
+	Notif {
+		/// Generation of the substream the message has been received on.
+		generation: u64,
+		/// The message.
+		message: BytesMut,
+	},
+
+	/// Received several messages on the notifications substream, in order. Emitted instead of
+	/// `Notif` if batching is enabled, in which case it contains at least one message.
+	///
+	/// Can only happen after an `Accept` and before a `Closed` of the same generation.
+	NotifBatch {
+		/// Generation of the substream the messages have been received on.
+		generation: u64,
+		/// The messages.
+		messages: Vec<BytesMut>,
+	},
+
+	/// The remote has violated the protocol, for example by sending a notification above the
+	/// maximum allowed size. The substream has been closed, and this event is emitted instead
+	/// of `Closed`.
+	///
+	/// Can only happen after an `Accept`.
+	ProtocolViolation {
+		/// Generation of the substream that has been closed.
+		generation: u64,
+		/// How the remote has violated the protocol.
+		error: NotificationsInError,
+	},
+
+	/// An error happened on the substream. The substream has been closed, and this event is
+	/// emitted instead of `Closed`.
+	///
+	/// Can only happen after an `Accept`.
+	Error {
+		/// Generation of the substream that has been closed.
+		generation: u64,
+		/// The error that happened.
+		error: NotifsInError,
+	},
+
+	/// Failed to upgrade a substream. Doesn't concern any of the substreams reported with an
+	/// `OpenRequest`.
+	UpgradeError(ProtocolsHandlerUpgrErr<void::Void>),
+
+	/// The remote has opened more substreams than allowed within the configured window. This is
+	/// emitted at most once per window, and doesn't affect the substream.
+	Flapping {
+		/// Number of substreams opened within the window.
+		opens_in_window: usize,
+	},
+
+	/// The remote has opened a substream while the previous one was still open or waiting for
+	/// an answer. The new substream has been dropped, and the previous one isn't affected. Only
+	/// emitted with [`NotifsInDuplicatePolicy::RejectAndReport`].
+	///
+	/// This can be considered as a protocol violation.
+	DuplicateSubstream {
+		/// Generation of the previous substream, which is still open or waiting for an answer.
+		generation: u64,
+	},
+
+	/// The remote has been sending notifications above the configured rate limit for too long.
+	/// The substream has been closed, and this event is emitted instead of `Closed`.
+	///
+	/// Can only happen after an `Accept`.
+	RateLimitExceeded {
+		/// Generation of the substream that has been closed.
+		generation: u64,
+	},
+
+	/// A shutdown started with `Shutdown` is over, and the connection can be closed.
+	ShutdownComplete {
+		/// True if some substreams couldn't be properly closed in time and have been dropped.
+		timed_out: bool,
+	},
+
+	/// Periodic report of the statistics of the handler. Only emitted if enabled with
+	/// [`NotifsInHandlerProto::with_stats_interval`].
+	Stats(NotifsInStats),
+
+	/// Periodic report of the bandwidth used by the notifications. Only emitted if enabled with
+	/// [`NotifsInHandlerProto::with_bandwidth_report_interval`], and never for an interval during
+	/// which nothing has been received.
+	BytesReceivedReport {
+		/// Total size, in bytes, of the notifications received since the previous report.
+		since_last: u64,
+	},
 }
 
 impl NotifsInHandlerProto {
 	/// Builds a new `NotifsInHandlerProto`.
+	///
+	/// `handshake` is the handshake sent back to the remote when a substream is accepted with
+	/// [`NotifsInHandlerIn::AcceptDefault`]. Inbound substreams whose handshake is above
+	/// `max_handshake_size` bytes are rejected during the upgrade. The handshakes sent back, on
+	/// the other hand, must never be above `DEFAULT_MAX_HANDSHAKE_SIZE` bytes, whatever the value
+	/// of `max_handshake_size`. Notifications above `max_notification_size` bytes are considered
+	/// as a protocol violation. Substreams are automatically refused if no `Accept` or `Refuse`
+	/// is received within `accept_refuse_timeout` after the corresponding `OpenRequest`.
 	pub fn new(
-		protocol_name: impl Into<Cow<'static, [u8]>>
+		protocol_name: impl Into<Cow<'static, [u8]>>,
+		handshake: impl Into<Vec<u8>>,
+		max_handshake_size: usize,
+		max_notification_size: u64,
+		accept_refuse_timeout: Duration,
 	) -> Self {
 		NotifsInHandlerProto {
-			in_protocol: NotificationsIn::new(protocol_name),
+			in_protocol: NotificationsIn::new(protocol_name, max_handshake_size, max_notification_size),
+			handshake: handshake.into(),
+			accept_refuse_timeout,
+			rate_limit: None,
+			keep_alive_grace: Duration::from_secs(0),
+			stats_interval: None,
+			bandwidth_report_interval: None,
+			metrics: None,
+			batching: None,
+			flapping: None,
+			poll_budget: None,
+			ack_window: None,
+			idle_timeout: None,
+			duplicate_policy: NotifsInDuplicatePolicy::RejectNew,
+			shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
 		}
 	}
+
+	/// Keeps the connection alive for `grace` after the substream has closed, in order to give
+	/// the remote the chance to re-open it without the connection being torn down.
+	///
+	/// The default is 0, in which case the connection is no longer kept alive as soon as the
+	/// substream closes.
+	pub fn with_keep_alive_grace(mut self, grace: Duration) -> Self {
+		self.keep_alive_grace = grace;
+		self
+	}
+
+	/// Also accepts substreams negotiated with one of the given protocol names, in addition to
+	/// the main one. Typically used in order to keep accepting the previous name of a protocol
+	/// that has been renamed.
+	pub fn with_fallback_names(
+		mut self,
+		fallback_names: impl IntoIterator<Item = impl Into<Cow<'static, [u8]>>>
+	) -> Self {
+		self.in_protocol = self.in_protocol.with_fallback_names(fallback_names);
+		self
+	}
+
+	/// Accepts the given versions of the protocol, by order of preference, instead of the names
+	/// passed to `new` and `with_fallback_names`. The version that has been negotiated is
+	/// reported in the `OpenRequest`.
+	pub fn with_versions(mut self, versions: impl IntoIterator<Item = NotificationsVersion>) -> Self {
+		self.in_protocol = self.in_protocol.with_versions(versions);
+		self
+	}
+
+	/// Also accepts substreams whose notifications are compressed. Decompression is transparent,
+	/// and notifications that can't be decompressed within the configured limit are considered as
+	/// a protocol violation.
+	pub fn with_compression(mut self, config: CompressionConfig) -> Self {
+		self.in_protocol = self.in_protocol.with_compression(config);
+		self
+	}
+
+	/// Enables rate limiting of the notifications received on the substream.
+	///
+	/// While the remote is above the limits, we stop reading from the substream until enough
+	/// time has passed. Remotes that stay below the limits are never slowed down.
+	pub fn with_rate_limit(mut self, rate_limit: NotifsInRateLimit) -> Self {
+		self.rate_limit = Some(rate_limit);
+		self
+	}
+
+	/// Emits a `Stats` event every `interval`, so that the outside doesn't need to query the
+	/// handler in order to aggregate statistics. An `interval` below 10 milliseconds is treated
+	/// as 10 milliseconds.
+	pub fn with_stats_interval(mut self, interval: Duration) -> Self {
+		self.stats_interval = Some(cmp::max(interval, MIN_REPORT_INTERVAL));
+		self
+	}
+
+	/// Emits a `BytesReceivedReport` event every `interval` during which notifications have been
+	/// received, so that the outside can find out which peers consume the most bandwidth. An
+	/// `interval` below 10 milliseconds is treated as 10 milliseconds.
+	pub fn with_bandwidth_report_interval(mut self, interval: Duration) -> Self {
+		self.bandwidth_report_interval = Some(cmp::max(interval, MIN_REPORT_INTERVAL));
+		self
+	}
+
+	/// Reports the traffic of the substream to the given Prometheus metrics.
+	pub fn with_metrics(mut self, metrics: Arc<NotifsInProtocolMetrics>) -> Self {
+		self.metrics = Some(metrics);
+		self
+	}
+
+	/// Delivers the notifications that are ready at the same time in a single `NotifBatch`
+	/// event, instead of one `Notif` event each.
+	pub fn with_batching(mut self, batching: NotifsInBatching) -> Self {
+		self.batching = Some(batching);
+		self
+	}
+
+	/// Emits a `Flapping` event when the remote opens substreams more often than allowed.
+	pub fn with_flapping_detection(mut self, flapping: NotifsInFlapping) -> Self {
+		self.flapping = Some(flapping);
+		self
+	}
+
+	/// After `budget` notifications have been read in a row without the substream ever being
+	/// pending, returns `Poll::Pending` once and immediately wakes up the task, in order to give
+	/// the other handlers of the connection a chance to make progress.
+	///
+	/// By default, there is no budget and notifications are read as long as they are available.
+	pub fn with_poll_budget(mut self, budget: usize) -> Self {
+		self.poll_budget = Some(budget);
+		self
+	}
+
+	/// Enables the acknowledgement mode. Every notification delivered to the outside must then
+	/// be acknowledged with a [`NotifsInHandlerIn::NotifAck`]. Once `window` notifications are
+	/// waiting for an acknowledgement, the substream isn't read anymore and the remote is
+	/// back-pressured. A `window` of 0 is treated as 1.
+	///
+	/// By default, notifications don't need to be acknowledged.
+	pub fn with_ack_window(mut self, window: usize) -> Self {
+		self.ack_window = Some(cmp::max(window, 1));
+		self
+	}
+
+	/// Also accepts substreams on which the remote sends keep-alive pings, and closes them with
+	/// [`NotifsInClosedReason::IdleTimeout`] if no frame, ping or notification, is received for
+	/// `timeout`.
+	///
+	/// Substreams on which the remote doesn't send pings are never closed for being idle. The
+	/// timeout isn't enforced while the substream isn't read, such as when paused.
+	pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+		self.in_protocol = self.in_protocol.with_keep_alive_pings();
+		self.idle_timeout = Some(timeout);
+		self
+	}
+
+	/// Sets what to do when the remote opens a substream while the previous one is still open
+	/// or waiting for an answer. The default is [`NotifsInDuplicatePolicy::RejectNew`].
+	pub fn with_duplicate_policy(mut self, policy: NotifsInDuplicatePolicy) -> Self {
+		self.duplicate_policy = policy;
+		self
+	}
+
+	/// Sets the maximum duration of a shutdown started with [`NotifsInHandlerIn::Shutdown`].
+	/// Substreams that haven't been properly closed by then are dropped.
+	///
+	/// The default is 5 seconds.
+	pub fn with_shutdown_timeout(mut self, timeout: Duration) -> Self {
+		self.shutdown_timeout = timeout;
+		self
+	}
 }
 
 impl IntoProtocolsHandler for NotifsInHandlerProto {
@@ -124,133 +835,1291 @@ impl IntoProtocolsHandler for NotifsInHandlerProto {
 	}
 
 	fn into_handler(self, _: &PeerId, _: &ConnectedPoint) -> Self::Handler {
+		self.build_handler()
+	}
+}
+
+impl NotifsInHandlerProto {
+	/// Builds the handler. Generic over the substream type so that tests can use the handler
+	/// with a mock substream.
+	fn build_handler<TSubstream>(self) -> NotifsInHandler<TSubstream> {
 		NotifsInHandler {
 			in_protocol: self.in_protocol,
-			substream: None,
-			pending_accept_refuses: 0,
-			events_queue: SmallVec::new(),
+			state: State::Closed,
+			handshake: self.handshake,
+			next_generation: 0,
+			closing_substreams: VecDeque::new(),
+			refusing_substreams: Vec::new(),
+			paused: false,
+			rate_limit: self.rate_limit.map(|limit| TokenBucket::new(limit, Instant::now())),
+			rate_limit_delay: None,
+			keep_alive_grace: self.keep_alive_grace,
+			keep_alive_until: None,
+			accept_refuse_timeout: self.accept_refuse_timeout,
+			stats: NotifsInStats::default(),
+			stats_timer: self.stats_interval.map(|interval| (Delay::new(interval), interval)),
+			bandwidth_timer: self.bandwidth_report_interval
+				.map(|interval| (Delay::new(interval), interval)),
+			bytes_since_report: 0,
+			metrics: self.metrics,
+			batching: self.batching,
+			flapping: self.flapping.map(FlappingDetector::new),
+			poll_budget: self.poll_budget,
+			notifs_in_a_row: 0,
+			ack_window: self.ack_window,
+			unacked: 0,
+			idle_timeout: self.idle_timeout,
+			idle_timer: None,
+			duplicate_policy: self.duplicate_policy,
+			shutdown_timeout: self.shutdown_timeout,
+			shutdown_deadline: None,
+			shutdown_complete: false,
+			draining: Vec::new(),
+			events_queue: VecDeque::new(),
+			waker: None,
 		}
 	}
 }
 
-impl NotifsInHandler {
+impl<TSubstream> NotifsInHandler<TSubstream> {
 	/// Returns the name of the protocol that we accept.
 	pub fn protocol_name(&self) -> &[u8] {
 		self.in_protocol.protocol_name()
 	}
-}
 
-impl ProtocolsHandler for NotifsInHandler {
-	type InEvent = NotifsInHandlerIn;
-	type OutEvent = NotifsInHandlerOut;
-	type Error = void::Void;
-	type InboundProtocol = NotificationsIn;
-	type OutboundProtocol = DeniedUpgrade;
-	type OutboundOpenInfo = ();
+	/// Returns the names of all the protocols that we accept, starting with the main one.
+	pub fn protocol_names(&self) -> impl Iterator<Item = &[u8]> {
+		self.in_protocol.protocol_names()
+	}
 
-	fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol> {
-		SubstreamProtocol::new(self.in_protocol.clone())
+	/// Returns the state of the inbound substream.
+	///
+	/// A new substream can be opened by the remote while a previous one is still being closed,
+	/// in which case the state of the new substream is returned.
+	pub fn state(&self) -> NotifsInState {
+		match self.state {
+			State::PendingAcceptRefuse { .. } => NotifsInState::PendingAcceptRefuse,
+			State::Open { .. } => NotifsInState::Open,
+			State::Closed | State::Poisoned if !self.closing_substreams.is_empty() =>
+				NotifsInState::Closing,
+			State::Closed | State::Poisoned => NotifsInState::Closed,
+		}
 	}
 
-	fn inject_fully_negotiated_inbound(
-		&mut self,
-		(msg, proto): <Self::InboundProtocol as InboundUpgrade<NegotiatedSubstream>>::Output
-	) {
-		if self.substream.is_some() {
-			warn!(
+	/// Returns true if the substream has been accepted and is currently open.
+	pub fn is_open(&self) -> bool {
+		self.state() == NotifsInState::Open
+	}
+
+	/// Returns the statistics about the notifications received so far.
+	pub fn stats(&self) -> NotifsInStats {
+		self.stats.clone()
+	}
+}
+
+impl<TSubstream> NotifsInHandler<TSubstream>
+where TSubstream: AsyncRead + AsyncWrite + Unpin,
+{
+	/// Called when an inbound substream has been negotiated. See
+	/// `ProtocolsHandler::inject_fully_negotiated_inbound`.
+	fn inject_substream(&mut self, msg: Vec<u8>, proto: NotificationsInSubstream<TSubstream>) {
+		if self.shutdown_deadline.is_some() || self.shutdown_complete {
+			debug!(
 				target: "sub-libp2p",
-				"Received duplicate inbound notifications substream for {:?}",
+				"Dropped inbound notifications substream for {:?} during shutdown",
 				str::from_utf8(self.in_protocol.protocol_name()),
 			);
 			return;
 		}
 
-		self.substream = Some(proto);
-		self.events_queue.push(ProtocolsHandlerEvent::Custom(NotifsInHandlerOut::OpenRequest(msg)));
-		self.pending_accept_refuses = self.pending_accept_refuses
-			.checked_add(1)
-			.unwrap_or_else(|| {
-				error!(target: "sub-libp2p", "Overflow in pending_accept_refuses");
-				usize::max_value()
-			});
-	}
-
-	fn inject_fully_negotiated_outbound(
-		&mut self,
-		out: <Self::OutboundProtocol as OutboundUpgrade<NegotiatedSubstream>>::Output,
-		_: Self::OutboundOpenInfo
-	) {
-		// We never emit any outgoing substream.
-		void::unreachable(out)
-	}
-
-	fn inject_event(&mut self, message: NotifsInHandlerIn) {
-		self.pending_accept_refuses = match self.pending_accept_refuses.checked_sub(1) {
-			Some(v) => v,
-			None => {
-				error!(
+		if let Some(flapping) = self.flapping.as_mut() {
+			if let Some(opens_in_window) = flapping.record_open(Instant::now()) {
+				warn!(
 					target: "sub-libp2p",
-					"Inconsistent state: received Accept/Refuse when no pending request exists"
+					"Remote opened {} inbound notifications substreams for {:?} in a short time",
+					opens_in_window,
+					str::from_utf8(self.in_protocol.protocol_name()),
 				);
-				return;
+				let event = NotifsInHandlerOut::Flapping { opens_in_window };
+				self.events_queue.push_back(ProtocolsHandlerEvent::Custom(event));
 			}
-		};
-
-		// If we send multiple `OpenRequest`s in a row, we will receive back multiple
-		// `Accept`/`Refuse` messages. All of them are obsolete except the last one.
-		if self.pending_accept_refuses != 0 {
-			return;
 		}
 
-		match (message, self.substream.as_mut()) {
-			(NotifsInHandlerIn::Accept(message), Some(sub)) => sub.send_handshake(message),
-			(NotifsInHandlerIn::Accept(_), None) => {},
-			(NotifsInHandlerIn::Refuse, _) => self.substream = None,
+		match self.state {
+			State::Closed => {},
+			State::PendingAcceptRefuse { generation, .. } | State::Open { generation, .. }
+				if self.duplicate_policy == NotifsInDuplicatePolicy::ReplaceOld =>
+			{
+				debug!(
+					target: "sub-libp2p",
+					"Inbound notifications substream for {:?} replaced by a new one",
+					str::from_utf8(self.in_protocol.protocol_name()),
+				);
+				// The answer to the `OpenRequest` of the previous substream, if it arrives, is
+				// ignored because of its generation.
+				if let State::PendingAcceptRefuse { .. } = self.state {
+					self.report_refused();
+				}
+				self.close_substream();
+				let event = NotifsInHandlerOut::Closed {
+					generation,
+					reason: NotifsInClosedReason::Replaced,
+				};
+				self.events_queue.push_back(ProtocolsHandlerEvent::Custom(event));
+			},
+			State::PendingAcceptRefuse { generation, .. } | State::Open { generation, .. } => {
+				warn!(
+					target: "sub-libp2p",
+					"Received duplicate inbound notifications substream for {:?}",
+					str::from_utf8(self.in_protocol.protocol_name()),
+				);
+				if self.duplicate_policy == NotifsInDuplicatePolicy::RejectAndReport {
+					let event = NotifsInHandlerOut::DuplicateSubstream { generation };
+					self.events_queue.push_back(ProtocolsHandlerEvent::Custom(event));
+					self.wake();
+				}
+				return;
+			},
+			State::Poisoned => {
+				error!(target: "sub-libp2p", "Notifications in handler is in poisoned state");
+				return;
+			},
 		}
-	}
 
-	fn inject_dial_upgrade_error(&mut self, _: (), _: ProtocolsHandlerUpgrErr<void::Void>) {
-		error!(target: "sub-libp2p", "Received dial upgrade error in inbound-only handler");
-	}
+		let generation = self.next_generation;
+		self.next_generation += 1;
 
-	fn connection_keep_alive(&self) -> KeepAlive {
-		if self.substream.is_some() {
-			KeepAlive::Yes
-		} else {
-			KeepAlive::No
+		let event = NotifsInHandlerOut::OpenRequest {
+			handshake: msg,
+			protocol_name: proto.protocol_name().clone(),
+			version: proto.version(),
+			generation,
+		};
+
+		self.state = State::PendingAcceptRefuse {
+			substream: proto,
+			generation,
+			deadline: Delay::new(self.accept_refuse_timeout),
+		};
+		self.paused = false;
+		self.unacked = 0;
+		self.rate_limit_delay = None;
+		if let Some(rate_limit) = self.rate_limit.as_mut() {
+			rate_limit.reset(Instant::now());
 		}
+		self.events_queue.push_back(ProtocolsHandlerEvent::Custom(event));
+		self.wake();
 	}
 
-	fn poll(
-		&mut self,
-		cx: &mut Context,
-	) -> Poll<
-		ProtocolsHandlerEvent<Self::OutboundProtocol, Self::OutboundOpenInfo, Self::OutEvent, Self::Error>
-	> {
-		// Flush the events queue if necessary.
-		if !self.events_queue.is_empty() {
-			let event = self.events_queue.remove(0);
-			return Poll::Ready(event)
-		}
+	/// Called when a message is received from the outside. See `ProtocolsHandler::inject_event`.
+	fn handle_event(&mut self, message: NotifsInHandlerIn) {
+		// Every message can change what `poll` does.
+		self.wake();
 
-		match self.substream.as_mut().map(|s| Stream::poll_next(Pin::new(s), cx)) {
-			None | Some(Poll::Pending) => {},
-			Some(Poll::Ready(Some(Ok(msg)))) =>
-				return Poll::Ready(ProtocolsHandlerEvent::Custom(NotifsInHandlerOut::Notif(msg))),
-			Some(Poll::Ready(None)) | Some(Poll::Ready(Some(Err(_)))) => {
-				self.substream = None;
-				return Poll::Ready(ProtocolsHandlerEvent::Custom(NotifsInHandlerOut::Closed));
+		// Either the handshake to send back, or the reason of the refusal, if any.
+		let (generation, answer) = match message {
+			NotifsInHandlerIn::Accept { generation, handshake } => {
+				self.handshake = handshake.clone();
+				(generation, Ok(handshake))
 			},
-		}
+			NotifsInHandlerIn::AcceptDefault { generation } =>
+				(generation, Ok(self.handshake.clone())),
+			NotifsInHandlerIn::UpdateHandshake { handshake } => {
+				self.handshake = handshake;
+				return;
+			},
+			NotifsInHandlerIn::Refuse { generation } => (generation, Err(None)),
+			NotifsInHandlerIn::RefuseWithReason { generation, reason } =>
+				(generation, Err(Some(reason))),
+			NotifsInHandlerIn::Pause => {
+				// Pausing a closed substream is a no-op.
+				if self.substream_mut().is_some() {
+					self.paused = true;
+				}
+				return;
+			},
+			NotifsInHandlerIn::Resume => {
+				self.paused = false;
+				return;
+			},
+			NotifsInHandlerIn::NotifAck { generation } => {
+				match self.state {
+					State::Open { generation: current, .. } if current == generation =>
+						self.unacked = self.unacked.saturating_sub(1),
+					// Obsolete acknowledgement concerning a substream that has been closed.
+					_ => {},
+				}
+				return;
+			},
+			NotifsInHandlerIn::Close => {
+				match self.state {
+					// Counts as a refusal of the pending `OpenRequest`.
+					State::PendingAcceptRefuse { .. } => self.report_refused(),
+					State::Open { .. } => {},
+					// Closing a closed substream is a no-op.
+					State::Closed | State::Poisoned => return,
+				}
 
-		Poll::Pending
-	}
-}
+				if let State::PendingAcceptRefuse { substream, generation, .. } |
+					State::Open { substream, generation } = mem::replace(&mut self.state, State::Closed)
+				{
+					self.closing_substreams.push_back((substream, generation));
+				}
+				return;
+			},
+			NotifsInHandlerIn::Shutdown => {
+				if self.shutdown_deadline.is_some() || self.shutdown_complete {
+					return;
+				}
 
-impl fmt::Debug for NotifsInHandler {
-	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-		f.debug_struct("NotifsInHandler")
-			.field("substream_open", &self.substream.is_some())
-			.finish()
-	}
-}
+				match mem::replace(&mut self.state, State::Closed) {
+					State::PendingAcceptRefuse { substream, .. } => {
+						self.report_refused();
+						self.draining.push(substream);
+					},
+					State::Open { substream, generation } => {
+						self.draining.push(substream);
+						let event = NotifsInHandlerOut::Closed {
+							generation,
+							reason: NotifsInClosedReason::Shutdown,
+						};
+						self.events_queue.push_back(ProtocolsHandlerEvent::Custom(event));
+					},
+					State::Closed => {},
+					State::Poisoned =>
+						error!(target: "sub-libp2p", "Notifications in handler is in poisoned state"),
+				}
+
+				self.shutdown_deadline = Some(Delay::new(self.shutdown_timeout));
+				return;
+			},
+		};
+
+		if generation >= self.next_generation {
+			error!(
+				target: "sub-libp2p",
+				"Inconsistent state: received Accept/Refuse for a substream that doesn't exist"
+			);
+			return;
+		}
+
+		self.state = match mem::replace(&mut self.state, State::Poisoned) {
+			State::PendingAcceptRefuse { mut substream, generation: current, .. }
+				if current == generation => match answer {
+				Ok(handshake) => {
+					self.stats.open_requests_accepted =
+						self.stats.open_requests_accepted.saturating_add(1);
+					substream.send_handshake(handshake);
+					State::Open { substream, generation }
+				},
+				Err(reason) => {
+					if let Some(reason) = reason {
+						if self.refusing_substreams.len() < MAX_REFUSING_SUBSTREAMS {
+							substream.send_refusal(reason);
+							self.refusing_substreams.push(substream);
+						}
+					}
+					self.report_refused();
+					self.keep_alive_until = Some(Instant::now() + self.keep_alive_grace);
+					State::Closed
+				},
+			},
+			st @ State::Open { generation: current, .. } if current == generation => {
+				error!(
+					target: "sub-libp2p",
+					"Inconsistent state: received Accept/Refuse for a substream already accepted"
+				);
+				st
+			},
+			State::Poisoned => {
+				error!(target: "sub-libp2p", "Notifications in handler is in poisoned state");
+				State::Poisoned
+			},
+			// Obsolete answer concerning a substream that has already been closed.
+			st => st,
+		};
+	}
+
+	/// See `ProtocolsHandler::connection_keep_alive`.
+	fn keep_alive(&self) -> KeepAlive {
+		if self.shutdown_deadline.is_some() {
+			return KeepAlive::Yes;
+		}
+		if self.shutdown_complete {
+			return KeepAlive::No;
+		}
+
+		match self.state {
+			State::PendingAcceptRefuse { .. } | State::Open { .. } => return KeepAlive::Yes,
+			State::Closed | State::Poisoned => {},
+		}
+
+		if !self.closing_substreams.is_empty() || !self.refusing_substreams.is_empty() {
+			return KeepAlive::Yes;
+		}
+
+		match self.keep_alive_until {
+			Some(until) if until > Instant::now() => KeepAlive::Until(until),
+			_ => KeepAlive::No,
+		}
+	}
+
+	/// Returns the substream, if any, whether it has been accepted or not.
+	fn substream_mut(&mut self) -> Option<&mut NotificationsInSubstream<TSubstream>> {
+		match &mut self.state {
+			State::PendingAcceptRefuse { substream, .. } | State::Open { substream, .. } =>
+				Some(substream),
+			State::Closed | State::Poisoned => None,
+		}
+	}
+
+	/// Returns the generation of the substream, if any, whether it has been accepted or not.
+	fn current_generation(&self) -> Option<u64> {
+		match self.state {
+			State::PendingAcceptRefuse { generation, .. } | State::Open { generation, .. } =>
+				Some(generation),
+			State::Closed | State::Poisoned => None,
+		}
+	}
+
+	/// Updates the rate limit, statistics and metrics after a notification has been received.
+	fn report_notif(&mut self, msg: &BytesMut) {
+		let now = Instant::now();
+		if let Some(rate_limit) = self.rate_limit.as_mut() {
+			rate_limit.consume(msg.len(), now);
+		}
+		self.stats.notifications_received = self.stats.notifications_received.saturating_add(1);
+		self.stats.bytes_received = self.stats.bytes_received.saturating_add(msg.len() as u64);
+		self.bytes_since_report = self.bytes_since_report.saturating_add(msg.len() as u64);
+		self.stats.last_notification = Some(now);
+		if let Some(metrics) = &self.metrics {
+			metrics.notifications_received.inc();
+			metrics.bytes_received.inc_by(msg.len() as u64);
+		}
+	}
+
+	/// Updates the statistics and metrics after an `OpenRequest` has been refused.
+	fn report_refused(&mut self) {
+		self.stats.open_requests_refused = self.stats.open_requests_refused.saturating_add(1);
+		if let Some(metrics) = &self.metrics {
+			metrics.open_requests_refused.inc();
+		}
+	}
+
+	/// Drops the substream, if any, and starts the keep-alive grace period.
+	///
+	/// If the substream hasn't been accepted or refused yet, the answer to its `OpenRequest`
+	/// will be ignored.
+	fn close_substream(&mut self) {
+		match self.state {
+			State::PendingAcceptRefuse { .. } | State::Open { .. } => {},
+			State::Closed | State::Poisoned => return,
+		}
+
+		self.state = State::Closed;
+		self.keep_alive_until = Some(Instant::now() + self.keep_alive_grace);
+	}
+
+	/// Returns true if the acknowledgement mode is enabled and the maximum number of
+	/// notifications waiting for an acknowledgement has been reached.
+	fn ack_window_full(&self) -> bool {
+		match self.ack_window {
+			Some(window) => self.unacked >= window,
+			None => false,
+		}
+	}
+
+	/// Called when opening an outbound substream failed. See
+	/// `ProtocolsHandler::inject_dial_upgrade_error`.
+	fn inject_upgrade_error(&mut self, err: ProtocolsHandlerUpgrErr<void::Void>) {
+		let event = NotifsInHandlerOut::UpgradeError(err);
+		self.events_queue.push_back(ProtocolsHandlerEvent::Custom(event));
+		self.wake();
+	}
+
+	/// Wakes up the task that last polled the handler, if any.
+	fn wake(&mut self) {
+		if let Some(waker) = self.waker.take() {
+			waker.wake();
+		}
+	}
+
+	/// See `ProtocolsHandler::poll`.
+	fn poll_event(
+		&mut self,
+		cx: &mut Context,
+	) -> Poll<ProtocolsHandlerEvent<DeniedUpgrade, (), NotifsInHandlerOut, void::Void>> {
+		match &self.waker {
+			Some(waker) if waker.will_wake(cx.waker()) => {},
+			_ => self.waker = Some(cx.waker().clone()),
+		}
+
+		// Flush the events queue if necessary. Only one event can be returned at a time, so we
+		// wake up the task immediately if more are waiting in order to be polled again.
+		if let Some(event) = self.events_queue.pop_front() {
+			if !self.events_queue.is_empty() {
+				cx.waker().wake_by_ref();
+			}
+			return Poll::Ready(event)
+		}
+
+		// Report the statistics if it is time to. The timer is polled only once, and we wake up
+		// the task after resetting it in order for the next call to poll it again, so that we
+		// are woken up at the end of the next interval.
+		if let Some((timer, interval)) = self.stats_timer.as_mut() {
+			if let Poll::Ready(()) = Pin::new(&mut *timer).poll(cx) {
+				timer.reset(*interval);
+				cx.waker().wake_by_ref();
+				let event = NotifsInHandlerOut::Stats(self.stats.clone());
+				return Poll::Ready(ProtocolsHandlerEvent::Custom(event));
+			}
+		}
+
+		// Report the bandwidth if it is time to. Same as for the statistics, except that the
+		// interval is skipped if nothing has been received.
+		if let Some((timer, interval)) = self.bandwidth_timer.as_mut() {
+			if let Poll::Ready(()) = Pin::new(&mut *timer).poll(cx) {
+				timer.reset(*interval);
+				cx.waker().wake_by_ref();
+				let since_last = mem::replace(&mut self.bytes_since_report, 0);
+				if since_last != 0 {
+					let event = NotifsInHandlerOut::BytesReceivedReport { since_last };
+					return Poll::Ready(ProtocolsHandlerEvent::Custom(event));
+				}
+			}
+		}
+
+		// Finish closing the substreams the outside has asked us to close. A `Closed` event is
+		// emitted for each of them once done.
+		let mut n = 0;
+		while n < self.closing_substreams.len() {
+			let (substream, generation) = &mut self.closing_substreams[n];
+			if let Poll::Ready(_) = NotificationsInSubstream::poll_close(Pin::new(substream), cx) {
+				let event = NotifsInHandlerOut::Closed {
+					generation: *generation,
+					reason: NotifsInClosedReason::Local,
+				};
+				self.events_queue.push_back(ProtocolsHandlerEvent::Custom(event));
+				self.closing_substreams.remove(n);
+				self.keep_alive_until = Some(Instant::now() + self.keep_alive_grace);
+			} else {
+				n += 1;
+			}
+		}
+		if let Some(event) = self.events_queue.pop_front() {
+			if !self.events_queue.is_empty() {
+				cx.waker().wake_by_ref();
+			}
+			return Poll::Ready(event);
+		}
+
+		// Finish sending the reason of the refusals. Errors are ignored, as the substreams are
+		// dropped anyway.
+		let mut n = 0;
+		while n < self.refusing_substreams.len() {
+			let substream = Pin::new(&mut self.refusing_substreams[n]);
+			if let Poll::Ready(_) = NotificationsInSubstream::poll_close(substream, cx) {
+				self.refusing_substreams.swap_remove(n);
+			} else {
+				n += 1;
+			}
+		}
+
+		// Drive the shutdown, if any. Completion is only reported once every substream has been
+		// properly closed, or once the deadline has been reached.
+		if let Some(deadline) = self.shutdown_deadline.as_mut() {
+			let timed_out = Pin::new(deadline).poll(cx).is_ready();
+
+			let mut n = 0;
+			while n < self.draining.len() {
+				let substream = Pin::new(&mut self.draining[n]);
+				if let Poll::Ready(_) = NotificationsInSubstream::poll_close(substream, cx) {
+					self.draining.swap_remove(n);
+				} else {
+					n += 1;
+				}
+			}
+
+			if timed_out {
+				warn!(
+					target: "sub-libp2p",
+					"Timeout while shutting down inbound notifications substreams for {:?}",
+					str::from_utf8(self.in_protocol.protocol_name()),
+				);
+				self.draining.clear();
+				self.refusing_substreams.clear();
+				for (_, generation) in self.closing_substreams.drain(..) {
+					let event = NotifsInHandlerOut::Closed {
+						generation,
+						reason: NotifsInClosedReason::Local,
+					};
+					self.events_queue.push_back(ProtocolsHandlerEvent::Custom(event));
+				}
+			}
+
+			if self.draining.is_empty() && self.refusing_substreams.is_empty() &&
+				self.closing_substreams.is_empty()
+			{
+				self.shutdown_deadline = None;
+				self.shutdown_complete = true;
+				let event = NotifsInHandlerOut::ShutdownComplete { timed_out };
+				self.events_queue.push_back(ProtocolsHandlerEvent::Custom(event));
+			}
+
+			if let Some(event) = self.events_queue.pop_front() {
+				if !self.events_queue.is_empty() {
+					cx.waker().wake_by_ref();
+				}
+				return Poll::Ready(event);
+			}
+		}
+
+		// Refuse the substream if the outside took too long to answer.
+		if let State::PendingAcceptRefuse { deadline, generation, .. } = &mut self.state {
+			if let Poll::Ready(()) = Pin::new(deadline).poll(cx) {
+				let generation = *generation;
+				warn!(
+					target: "sub-libp2p",
+					"Timeout while waiting for Accept/Refuse on inbound notifications substream for {:?}",
+					str::from_utf8(self.in_protocol.protocol_name()),
+				);
+				self.close_substream();
+				self.report_refused();
+				let event = NotifsInHandlerOut::RefusedByTimeout { generation };
+				return Poll::Ready(ProtocolsHandlerEvent::Custom(event));
+			}
+		}
+
+		// While paused, we don't read from the substream and let the remote get back-pressured.
+		// The handshake is still sent, so that the remote doesn't wait for it until we resume.
+		if self.paused {
+			if let State::Open { substream, generation } = &mut self.state {
+				let generation = *generation;
+				if let Poll::Ready(Err(err)) = Pin::new(substream).poll_send_handshake(cx) {
+					self.close_substream();
+					let event = NotifsInHandlerOut::Error { generation, error: err.into() };
+					return Poll::Ready(ProtocolsHandlerEvent::Custom(event));
+				}
+			}
+			return Poll::Pending;
+		}
+
+		// Same if the remote is sending notifications too quickly.
+		if let Some(generation) = self.current_generation() {
+			if let Some(rate_limit) = self.rate_limit.as_mut() {
+				loop {
+					match rate_limit.check(Instant::now()) {
+						RateLimitCheck::Allowed => {
+							self.rate_limit_delay = None;
+							break;
+						},
+						RateLimitCheck::Throttled(duration) => {
+							let delay = self.rate_limit_delay.get_or_insert_with(|| Delay::new(duration));
+							match Pin::new(delay).poll(cx) {
+								Poll::Ready(()) => self.rate_limit_delay = None,
+								Poll::Pending => return Poll::Pending,
+							}
+						},
+						RateLimitCheck::Exceeded => {
+							warn!(
+								target: "sub-libp2p",
+								"Rate limit exceeded on inbound notifications substream for {:?}",
+								str::from_utf8(self.in_protocol.protocol_name()),
+							);
+							self.close_substream();
+							self.rate_limit_delay = None;
+							let event = NotifsInHandlerOut::RateLimitExceeded { generation };
+							return Poll::Ready(ProtocolsHandlerEvent::Custom(event));
+						},
+					}
+				}
+			}
+		}
+
+		let generation = match self.current_generation() {
+			Some(generation) => generation,
+			None => return Poll::Pending,
+		};
+
+		// Yield if we have been reading for too long. We are polled again right away, but only
+		// after the other handlers of the connection.
+		if let Some(budget) = self.poll_budget {
+			if self.notifs_in_a_row >= budget {
+				self.notifs_in_a_row = 0;
+				cx.waker().wake_by_ref();
+				return Poll::Pending;
+			}
+		}
+
+		// In acknowledgement mode, the substream isn't read as long as the window is full. As a
+		// consequence, the remote closing the substream is only noticed after an acknowledgement.
+		if self.ack_window_full() {
+			return Poll::Pending;
+		}
+
+		// In batching mode, we keep reading as long as notifications are immediately available,
+		// but never wait for more to arrive.
+		let mut batch = Vec::new();
+		let mut batch_bytes = 0usize;
+		let end_event = loop {
+			match self.substream_mut().map(|s| Stream::poll_next(Pin::new(s), cx)) {
+				None => break None,
+				Some(Poll::Pending) => {
+					// The remote has nothing more to send for now.
+					if let Some(rate_limit) = self.rate_limit.as_mut() {
+						rate_limit.idle();
+					}
+					self.notifs_in_a_row = 0;
+					break None;
+				},
+				Some(Poll::Ready(Some(Ok(msg)))) => {
+					self.report_notif(&msg);
+					self.notifs_in_a_row = self.notifs_in_a_row.saturating_add(1);
+					if self.ack_window.is_some() {
+						self.unacked = self.unacked.saturating_add(1);
+					}
+					let batching = match self.batching {
+						Some(batching) => batching,
+						None => {
+							let event = NotifsInHandlerOut::Notif { generation, message: msg };
+							return Poll::Ready(ProtocolsHandlerEvent::Custom(event));
+						},
+					};
+
+					batch_bytes = batch_bytes.saturating_add(msg.len());
+					batch.push(msg);
+					if batch.len() >= batching.max_messages || batch_bytes >= batching.max_bytes ||
+						self.ack_window_full()
+					{
+						break None;
+					}
+					// The rate limit is checked again before the next read.
+					if let Some(rate_limit) = self.rate_limit.as_mut() {
+						if rate_limit.check(Instant::now()) != RateLimitCheck::Allowed {
+							break None;
+						}
+					}
+				},
+				Some(Poll::Ready(Some(Err(err @ NotificationsInError::TooLarge { .. })))) |
+				Some(Poll::Ready(Some(Err(err @ NotificationsInError::Decompression { .. })))) |
+				Some(Poll::Ready(Some(Err(err @ NotificationsInError::UnknownFrameTag { .. })))) => {
+					self.close_substream();
+					break Some(NotifsInHandlerOut::ProtocolViolation { generation, error: err });
+				},
+				Some(Poll::Ready(Some(Err(NotificationsInError::Io(err))))) => {
+					self.close_substream();
+					break Some(NotifsInHandlerOut::Error { generation, error: err.into() });
+				},
+				Some(Poll::Ready(None)) => {
+					self.close_substream();
+					break Some(NotifsInHandlerOut::Closed {
+						generation,
+						reason: NotifsInClosedReason::Remote,
+					});
+				},
+			}
+		};
+
+		// The notifications that have been read before the substream closed must be delivered
+		// first.
+		match (batch.is_empty(), end_event) {
+			(true, None) => {},
+			(true, Some(event)) => return Poll::Ready(ProtocolsHandlerEvent::Custom(event)),
+			(false, end_event) => {
+				if let Some(event) = end_event {
+					self.events_queue.push_back(ProtocolsHandlerEvent::Custom(event));
+					cx.waker().wake_by_ref();
+				}
+				let event = NotifsInHandlerOut::NotifBatch { generation, messages: batch };
+				return Poll::Ready(ProtocolsHandlerEvent::Custom(event));
+			},
+		}
+
+		// Everything available has been read. Close the substream if the remote is supposed to
+		// send pings but has been silent for too long.
+		let idle_deadline = match (&self.state, self.idle_timeout) {
+			(State::Open { substream, .. }, Some(timeout)) if substream.has_keep_alive_pings() =>
+				Some(substream.last_frame_received() + timeout),
+			_ => None,
+		};
+		if let Some(deadline) = idle_deadline {
+			let now = Instant::now();
+			if now < deadline {
+				let timer = self.idle_timer.get_or_insert_with(|| Delay::new(deadline - now));
+				timer.reset(deadline - now);
+				if let Poll::Ready(()) = Pin::new(timer).poll(cx) {
+					cx.waker().wake_by_ref();
+				}
+			} else {
+				warn!(
+					target: "sub-libp2p",
+					"Idle timeout on inbound notifications substream for {:?}",
+					str::from_utf8(self.in_protocol.protocol_name()),
+				);
+				self.close_substream();
+				self.idle_timer = None;
+				let event = NotifsInHandlerOut::Closed {
+					generation,
+					reason: NotifsInClosedReason::IdleTimeout,
+				};
+				return Poll::Ready(ProtocolsHandlerEvent::Custom(event));
+			}
+		}
+
+		Poll::Pending
+	}
+}
+
+impl ProtocolsHandler for NotifsInHandler {
+	type InEvent = NotifsInHandlerIn;
+	type OutEvent = NotifsInHandlerOut;
+	type Error = void::Void;
+	type InboundProtocol = NotificationsIn;
+	type OutboundProtocol = DeniedUpgrade;
+	type OutboundOpenInfo = ();
+
+	fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol> {
+		SubstreamProtocol::new(self.in_protocol.clone())
+	}
+
+	fn inject_fully_negotiated_inbound(
+		&mut self,
+		(msg, proto): <Self::InboundProtocol as InboundUpgrade<NegotiatedSubstream>>::Output
+	) {
+		self.inject_substream(msg, proto)
+	}
+
+	fn inject_fully_negotiated_outbound(
+		&mut self,
+		out: <Self::OutboundProtocol as OutboundUpgrade<NegotiatedSubstream>>::Output,
+		_: Self::OutboundOpenInfo
+	) {
+		// We never emit any outgoing substream.
+		void::unreachable(out)
+	}
+
+	fn inject_event(&mut self, message: NotifsInHandlerIn) {
+		self.handle_event(message)
+	}
+
+	fn inject_dial_upgrade_error(&mut self, _: (), err: ProtocolsHandlerUpgrErr<void::Void>) {
+		self.inject_upgrade_error(err)
+	}
+
+	fn connection_keep_alive(&self) -> KeepAlive {
+		self.keep_alive()
+	}
+
+	fn poll(
+		&mut self,
+		cx: &mut Context,
+	) -> Poll<
+		ProtocolsHandlerEvent<Self::OutboundProtocol, Self::OutboundOpenInfo, Self::OutEvent, Self::Error>
+	> {
+		self.poll_event(cx)
+	}
+}
+
+/// Implements the `IntoProtocolsHandler` trait of libp2p for several protocols at once.
+///
+/// See the documentation of [`NotifsInMultiHandler`] for more information.
+pub struct NotifsInMultiHandlerProto {
+	/// Configuration of each protocol, by index.
+	protocols: Vec<NotifsInHandlerProto>,
+}
+
+/// Handler for inbound substreams of several notifications protocols.
+///
+/// Each protocol behaves exactly like a [`NotifsInHandler`] and has its own independent state.
+/// Protocols are designated by their index in the list passed at initialization, and all the
+/// events are tagged with this index.
+pub struct NotifsInMultiHandler<TSubstream = NegotiatedSubstream> {
+	/// One handler per protocol, by index.
+	handlers: Vec<NotifsInHandler<TSubstream>>,
+
+	/// Index of the handler to poll first during the next call to `poll`. Rotates in order to
+	/// prevent a busy protocol from starving the others.
+	next_poll: usize,
+}
+
+/// Event that can be received by a `NotifsInMultiHandler`.
+#[derive(Debug)]
+pub struct NotifsInMultiHandlerIn {
+	/// Index of the protocol the event is destined to.
+	pub protocol_index: usize,
+	/// The event to deliver to the handler of this protocol.
+	pub event: NotifsInHandlerIn,
+}
+
+/// Event that can be emitted by a `NotifsInMultiHandler`.
+#[derive(Debug)]
+pub struct NotifsInMultiHandlerOut {
+	/// Index of the protocol the event relates to.
+	pub protocol_index: usize,
+	/// Main name of the protocol the event relates to.
+	pub protocol_name: Cow<'static, [u8]>,
+	/// The event emitted by the handler of this protocol.
+	pub event: NotifsInHandlerOut,
+}
+
+impl NotifsInMultiHandlerProto {
+	/// Builds a new `NotifsInMultiHandlerProto` supporting the given list of protocols, with
+	/// the same configuration for all of them. See [`NotifsInHandlerProto::new`].
+	///
+	/// In order to configure each protocol individually, build the list of
+	/// [`NotifsInHandlerProto`]s and collect it into a `NotifsInMultiHandlerProto` instead.
+	pub fn new(
+		protocol_names: impl IntoIterator<Item = impl Into<Cow<'static, [u8]>>>,
+		handshake: impl Into<Vec<u8>>,
+		max_handshake_size: usize,
+		max_notification_size: u64,
+		accept_refuse_timeout: Duration,
+	) -> Self {
+		let handshake = handshake.into();
+		protocol_names.into_iter()
+			.map(|name| NotifsInHandlerProto::new(
+				name,
+				handshake.clone(),
+				max_handshake_size,
+				max_notification_size,
+				accept_refuse_timeout,
+			))
+			.collect()
+	}
+}
+
+impl FromIterator<NotifsInHandlerProto> for NotifsInMultiHandlerProto {
+	fn from_iter<I: IntoIterator<Item = NotifsInHandlerProto>>(iter: I) -> Self {
+		NotifsInMultiHandlerProto {
+			protocols: iter.into_iter().collect(),
+		}
+	}
+}
+
+impl IntoProtocolsHandler for NotifsInMultiHandlerProto {
+	type Handler = NotifsInMultiHandler;
+
+	fn inbound_protocol(&self) -> UpgradeCollec<NotificationsIn> {
+		self.protocols.iter().map(|p| p.inbound_protocol()).collect()
+	}
+
+	fn into_handler(self, _: &PeerId, _: &ConnectedPoint) -> Self::Handler {
+		self.build_handler()
+	}
+}
+
+impl NotifsInMultiHandlerProto {
+	/// Builds the handler. Contrary to `into_handler`, works with any type of substream.
+	fn build_handler<TSubstream>(self) -> NotifsInMultiHandler<TSubstream> {
+		NotifsInMultiHandler {
+			handlers: self.protocols.into_iter().map(|p| p.build_handler()).collect(),
+			next_poll: 0,
+		}
+	}
+}
+
+impl<TSubstream> NotifsInMultiHandler<TSubstream> {
+	/// Returns the handler of the protocol with the given index, in order to inspect its state.
+	pub fn protocol(&self, protocol_index: usize) -> Option<&NotifsInHandler<TSubstream>> {
+		self.handlers.get(protocol_index)
+	}
+
+	/// Returns the number of protocols supported by this handler.
+	pub fn num_protocols(&self) -> usize {
+		self.handlers.len()
+	}
+}
+
+impl<TSubstream> NotifsInMultiHandler<TSubstream>
+where TSubstream: AsyncRead + AsyncWrite + Unpin,
+{
+	/// Called when an inbound substream has been negotiated for the protocol with the given
+	/// index. See `ProtocolsHandler::inject_fully_negotiated_inbound`.
+	fn inject_substream(
+		&mut self,
+		protocol_index: usize,
+		msg: Vec<u8>,
+		proto: NotificationsInSubstream<TSubstream>
+	) {
+		match self.handlers.get_mut(protocol_index) {
+			Some(handler) => handler.inject_substream(msg, proto),
+			None => error!(target: "sub-libp2p", "Substream negotiated for an unknown protocol"),
+		}
+	}
+
+	/// Called when opening an outbound substream for the protocol with the given index failed.
+	/// See `ProtocolsHandler::inject_dial_upgrade_error`.
+	fn inject_upgrade_error(
+		&mut self,
+		protocol_index: usize,
+		err: ProtocolsHandlerUpgrErr<void::Void>
+	) {
+		match self.handlers.get_mut(protocol_index) {
+			Some(handler) => handler.inject_upgrade_error(err),
+			None => error!(
+				target: "sub-libp2p",
+				"Upgrade error reported for unknown protocol index {}",
+				protocol_index,
+			),
+		}
+	}
+
+	/// See `ProtocolsHandler::inject_event`.
+	fn handle_event(&mut self, message: NotifsInMultiHandlerIn) {
+		match self.handlers.get_mut(message.protocol_index) {
+			Some(handler) => handler.handle_event(message.event),
+			None => error!(
+				target: "sub-libp2p",
+				"Event destined to unknown protocol index {}",
+				message.protocol_index,
+			),
+		}
+	}
+
+	/// See `ProtocolsHandler::connection_keep_alive`. Returns the maximum value of all the
+	/// protocols.
+	fn keep_alive(&self) -> KeepAlive {
+		let mut ret = KeepAlive::No;
+		for handler in &self.handlers {
+			let val = handler.keep_alive();
+			if val.is_yes() {
+				return KeepAlive::Yes;
+			}
+			if ret < val { ret = val; }
+		}
+		ret
+	}
+
+	/// See `ProtocolsHandler::poll`.
+	fn poll_event(
+		&mut self,
+		cx: &mut Context,
+	) -> Poll<ProtocolsHandlerEvent<DeniedUpgrade, usize, NotifsInMultiHandlerOut, void::Void>> {
+		let num_handlers = self.handlers.len();
+		for offset in 0..num_handlers {
+			let protocol_index = (self.next_poll + offset) % num_handlers;
+			let handler = &mut self.handlers[protocol_index];
+			match handler.poll_event(cx) {
+				Poll::Ready(ProtocolsHandlerEvent::Custom(event)) => {
+					self.next_poll = (protocol_index + 1) % num_handlers;
+					return Poll::Ready(ProtocolsHandlerEvent::Custom(NotifsInMultiHandlerOut {
+						protocol_index,
+						protocol_name: handler.in_protocol.protocol_name().clone(),
+						event,
+					}));
+				},
+				Poll::Ready(ProtocolsHandlerEvent::OutboundSubstreamRequest { protocol, info: () }) => {
+					// The index of the protocol is passed back to us if the upgrade fails.
+					let info = protocol_index;
+					let event = ProtocolsHandlerEvent::OutboundSubstreamRequest { protocol, info };
+					return Poll::Ready(event);
+				},
+				Poll::Ready(ProtocolsHandlerEvent::Close(err)) => void::unreachable(err),
+				Poll::Pending => {},
+			}
+		}
+
+		Poll::Pending
+	}
+}
+
+impl ProtocolsHandler for NotifsInMultiHandler {
+	type InEvent = NotifsInMultiHandlerIn;
+	type OutEvent = NotifsInMultiHandlerOut;
+	type Error = void::Void;
+	type InboundProtocol = UpgradeCollec<NotificationsIn>;
+	type OutboundProtocol = DeniedUpgrade;
+	type OutboundOpenInfo = usize;
+
+	fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol> {
+		let protocols = self.handlers.iter()
+			.map(|h| h.in_protocol.clone())
+			.collect::<UpgradeCollec<_>>();
+		SubstreamProtocol::new(protocols)
+	}
+
+	fn inject_fully_negotiated_inbound(
+		&mut self,
+		((msg, proto), num): <Self::InboundProtocol as InboundUpgrade<NegotiatedSubstream>>::Output
+	) {
+		self.inject_substream(num, msg, proto)
+	}
+
+	fn inject_fully_negotiated_outbound(
+		&mut self,
+		out: <Self::OutboundProtocol as OutboundUpgrade<NegotiatedSubstream>>::Output,
+		_: Self::OutboundOpenInfo
+	) {
+		// We never emit any outgoing substream.
+		void::unreachable(out)
+	}
+
+	fn inject_event(&mut self, message: NotifsInMultiHandlerIn) {
+		self.handle_event(message)
+	}
+
+	fn inject_dial_upgrade_error(
+		&mut self,
+		protocol_index: usize,
+		err: ProtocolsHandlerUpgrErr<void::Void>
+	) {
+		self.inject_upgrade_error(protocol_index, err)
+	}
+
+	fn connection_keep_alive(&self) -> KeepAlive {
+		self.keep_alive()
+	}
+
+	fn poll(
+		&mut self,
+		cx: &mut Context,
+	) -> Poll<
+		ProtocolsHandlerEvent<Self::OutboundProtocol, Self::OutboundOpenInfo, Self::OutEvent, Self::Error>
+	> {
+		self.poll_event(cx)
+	}
+}
+
+/// Token bucket enforcing a [`NotifsInRateLimit`].
+///
+/// The current time is always passed as parameter, which makes it possible to test this struct
+/// with a mock clock.
+struct TokenBucket {
+	/// Limits to enforce.
+	limit: NotifsInRateLimit,
+	/// Number of notifications that can still be received. Can't go above
+	/// `limit.messages_per_sec`, but can go below 0.
+	messages: f64,
+	/// Number of bytes that can still be received. Can't go above `limit.bytes_per_sec`, but can
+	/// go below 0.
+	bytes: f64,
+	/// Last time `messages` and `bytes` have been refilled.
+	last_refill: Instant,
+	/// If `Some`, the remote has been continuously sending at or above the limits since then.
+	saturated_since: Option<Instant>,
+}
+
+/// Outcome of [`TokenBucket::check`].
+#[derive(Debug, PartialEq)]
+enum RateLimitCheck {
+	/// We can read the next notification.
+	Allowed,
+	/// We must wait for the given duration before reading the next notification.
+	Throttled(Duration),
+	/// The remote has been above the limits for longer than allowed.
+	Exceeded,
+}
+
+impl TokenBucket {
+	/// Builds a new bucket, initially full.
+	fn new(mut limit: NotifsInRateLimit, now: Instant) -> Self {
+		limit.messages_per_sec = cmp::max(limit.messages_per_sec, 1);
+		limit.bytes_per_sec = cmp::max(limit.bytes_per_sec, 1);
+
+		TokenBucket {
+			messages: f64::from(limit.messages_per_sec),
+			bytes: limit.bytes_per_sec as f64,
+			last_refill: now,
+			saturated_since: None,
+			limit,
+		}
+	}
+
+	/// Resets the bucket to its initial state.
+	fn reset(&mut self, now: Instant) {
+		*self = TokenBucket::new(self.limit.clone(), now);
+	}
+
+	/// Adds the tokens accumulated since the last refill.
+	fn refill(&mut self, now: Instant) {
+		if now <= self.last_refill {
+			return;
+		}
+
+		let elapsed = (now - self.last_refill).as_secs_f64();
+		let max_messages = f64::from(self.limit.messages_per_sec);
+		let max_bytes = self.limit.bytes_per_sec as f64;
+		self.messages = (self.messages + elapsed * max_messages).min(max_messages);
+		self.bytes = (self.bytes + elapsed * max_bytes).min(max_bytes);
+		self.last_refill = now;
+	}
+
+	/// Records a notification of `len` bytes as received.
+	fn consume(&mut self, len: usize, now: Instant) {
+		self.refill(now);
+		self.messages -= 1.0;
+		self.bytes -= len as f64;
+	}
+
+	/// Records that the remote has nothing more to send for now.
+	fn idle(&mut self) {
+		self.saturated_since = None;
+	}
+
+	/// Checks whether we are allowed to read the next notification.
+	fn check(&mut self, now: Instant) -> RateLimitCheck {
+		self.refill(now);
+
+		let wait_messages = (1.0 - self.messages) / f64::from(self.limit.messages_per_sec);
+		let wait_bytes = -self.bytes / self.limit.bytes_per_sec as f64;
+		let wait = wait_messages.max(wait_bytes);
+		if wait <= 0.0 {
+			return RateLimitCheck::Allowed;
+		}
+
+		let saturated_since = *self.saturated_since.get_or_insert(now);
+		if now >= saturated_since && now - saturated_since >= self.limit.max_saturation {
+			return RateLimitCheck::Exceeded;
+		}
+
+		RateLimitCheck::Throttled(Duration::from_secs_f64(wait))
+	}
+}
+
+/// Counts the substreams opened by the remote within a sliding window.
+///
+/// The current time is always passed as parameter, which makes it possible to test this struct
+/// with a mock clock.
+struct FlappingDetector {
+	/// Configuration.
+	config: NotifsInFlapping,
+	/// When the substreams within the window have been opened, oldest first.
+	opens: VecDeque<Instant>,
+	/// Last time flapping has been reported, if any.
+	last_report: Option<Instant>,
+}
+
+impl FlappingDetector {
+	fn new(config: NotifsInFlapping) -> Self {
+		FlappingDetector {
+			config,
+			opens: VecDeque::new(),
+			last_report: None,
+		}
+	}
+
+	/// Records that a substream has been opened. Returns the number of substreams opened within
+	/// the window if flapping must be reported.
+	fn record_open(&mut self, now: Instant) -> Option<usize> {
+		while let Some(oldest) = self.opens.front() {
+			if *oldest + self.config.window > now {
+				break;
+			}
+			self.opens.pop_front();
+		}
+
+		self.opens.push_back(now);
+
+		if self.opens.len() <= self.config.max_opens {
+			return None;
+		}
+
+		match self.last_report {
+			Some(last) if last + self.config.window > now => None,
+			_ => {
+				self.last_report = Some(now);
+				Some(self.opens.len())
+			},
+		}
+	}
+}
+
+impl NotifsInMetrics {
+	/// Registers the metrics in the given registry.
+	pub fn register(registry: &Registry) -> Result<Self, PrometheusError> {
+		Ok(NotifsInMetrics {
+			notifications_received: register(CounterVec::new(
+				Opts::new(
+					"notifications_received_total",
+					"Number of notifications received, per protocol"
+				),
+				&["protocol"]
+			)?, registry)?,
+			bytes_received: register(CounterVec::new(
+				Opts::new(
+					"notifications_received_bytes_total",
+					"Total size of the notifications received, per protocol"
+				),
+				&["protocol"]
+			)?, registry)?,
+			open_requests_refused: register(CounterVec::new(
+				Opts::new(
+					"notifications_open_requests_refused_total",
+					"Number of inbound notifications substreams refused, per protocol"
+				),
+				&["protocol"]
+			)?, registry)?,
+			protocols: FnvHashMap::default(),
+		})
+	}
+
+	/// Creates the metrics of the given protocol, if they don't exist yet.
+	pub fn add_protocol(&mut self, protocol_name: Cow<'static, [u8]>) {
+		if self.protocols.contains_key(&protocol_name) {
+			return;
+		}
+
+		let label = String::from_utf8_lossy(&protocol_name).into_owned();
+		let metrics = NotifsInProtocolMetrics {
+			notifications_received: self.notifications_received.with_label_values(&[label.as_str()]),
+			bytes_received: self.bytes_received.with_label_values(&[label.as_str()]),
+			open_requests_refused: self.open_requests_refused.with_label_values(&[label.as_str()]),
+		};
+		self.protocols.insert(protocol_name, Arc::new(metrics));
+	}
+
+	/// Returns the metrics of the given protocol, if it has been added with `add_protocol`.
+	pub fn protocol(&self, protocol_name: &[u8]) -> Option<Arc<NotifsInProtocolMetrics>> {
+		self.protocols.get(protocol_name).cloned()
+	}
+}
+
+impl<TSubstream> fmt::Debug for NotifsInHandler<TSubstream> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		let generation = match self.state {
+			State::PendingAcceptRefuse { generation, .. } | State::Open { generation, .. } =>
+				Some(generation),
+			State::Closed | State::Poisoned => None,
+		};
+
+		f.debug_struct("NotifsInHandler")
+			.field("protocol", &DebugProtocolName(self.in_protocol.protocol_name()))
+			.field("state", &self.state())
+			.field("substream_present", &generation.is_some())
+			.field("generation", &generation)
+			.field("paused", &self.paused)
+			.field("closing_substreams", &self.closing_substreams.len())
+			.field("refusing_substreams", &self.refusing_substreams.len())
+			.field("shutting_down", &self.shutdown_deadline.is_some())
+			.field("queued_events", &self.events_queue.len())
+			.field("notifications_received", &self.stats.notifications_received)
+			.field("bytes_received", &self.stats.bytes_received)
+			.finish()
+	}
+}
+
+/// Prints a protocol name as lossy UTF-8, truncated to `MAX_DEBUG_PROTOCOL_NAME_LEN` bytes.
+struct DebugProtocolName<'a>(&'a [u8]);
+
+impl<'a> fmt::Debug for DebugProtocolName<'a> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		let truncated = self.0.len() > MAX_DEBUG_PROTOCOL_NAME_LEN;
+		let name = &self.0[..cmp::min(self.0.len(), MAX_DEBUG_PROTOCOL_NAME_LEN)];
+		write!(f, "{:?}", String::from_utf8_lossy(name))?;
+		if truncated {
+			write!(f, "...")?;
+		}
+		Ok(())
+	}
+}
+
+impl<TSubstream> fmt::Debug for NotifsInMultiHandler<TSubstream> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		f.debug_struct("NotifsInMultiHandler")
+			.field("handlers", &self.handlers)
+			.finish()
+	}
+}
+
+#[cfg(test)]
+mod tests;
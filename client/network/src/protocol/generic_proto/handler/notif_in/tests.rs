@@ -0,0 +1,1647 @@
+// Copyright 2020 Parity Technologies (UK) Ltd.
+// This file is part of Substrate.
+
+// Substrate is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Substrate is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Substrate.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{NotifsInHandler, NotifsInHandlerIn, NotifsInHandlerOut, NotifsInHandlerProto};
+use super::{NotifsInBatching, NotifsInMetrics, NotifsInRateLimit, NotifsInState, NotifsInStats};
+use super::{FlappingDetector, NotifsInError, NotifsInFlapping, RateLimitCheck, State, TokenBucket};
+use super::{NotifsInMultiHandler, NotifsInMultiHandlerIn, NotifsInMultiHandlerOut};
+use super::{NotifsInMultiHandlerProto, NotifsInClosedReason, NotifsInDuplicatePolicy};
+use super::{MAX_DEBUG_PROTOCOL_NAME_LEN, MIN_REPORT_INTERVAL};
+use crate::protocol::generic_proto::upgrade::{
+	DEFAULT_MAX_HANDSHAKE_SIZE, NotificationsFraming, NotificationsIn, NotificationsInError,
+	NotificationsInSubstream, NotificationsVersion,
+};
+
+use futures::{executor, prelude::*, task::{self, ArcWake}};
+use libp2p::core::{ConnectedPoint, PeerId, upgrade::{DeniedUpgrade, InboundUpgrade}};
+use libp2p::swarm::{IntoProtocolsHandler, KeepAlive, ProtocolsHandler, ProtocolsHandlerEvent};
+use libp2p::swarm::ProtocolsHandlerUpgrErr;
+use std::sync::{Arc, Mutex, mpsc, atomic::{AtomicUsize, Ordering}};
+use std::{borrow::Cow, cmp, io, pin::Pin, task::{Context, Poll}, time::Duration};
+use substrate_prometheus_endpoint::Registry;
+use wasm_timer::Instant;
+
+const PROTO_NAME: &'static [u8] = b"/test/proto/1";
+const OTHER_PROTO_NAME: &'static [u8] = b"/test/other/1";
+
+/// Socket that yields the content of `to_read`, then returns `Pending` `pending_reads`
+/// times, then behaves according to `end`. Unless `stalled_writes` is true, in which case
+/// they never complete, writes always succeed and are appended to `written`.
+struct MockSocket {
+	to_read: Vec<u8>,
+	pending_reads: usize,
+	end: MockEnd,
+	written: Arc<Mutex<Vec<u8>>>,
+	stalled_writes: bool,
+}
+
+/// What a `MockSocket` does once everything has been read.
+#[derive(Copy, Clone)]
+enum MockEnd {
+	/// Pending forever.
+	Pending,
+	/// Reports EOF.
+	Eof,
+	/// Reports an error of the given kind.
+	Error(io::ErrorKind),
+}
+
+impl AsyncRead for MockSocket {
+	fn poll_read(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context,
+		buf: &mut [u8]
+	) -> Poll<Result<usize, io::Error>> {
+		if self.to_read.is_empty() && self.pending_reads > 0 {
+			// Behave as if more data was about to arrive.
+			self.pending_reads -= 1;
+			cx.waker().wake_by_ref();
+			return Poll::Pending;
+		}
+
+		if self.to_read.is_empty() {
+			return match self.end {
+				MockEnd::Pending => Poll::Pending,
+				MockEnd::Eof => Poll::Ready(Ok(0)),
+				MockEnd::Error(kind) => Poll::Ready(Err(kind.into())),
+			};
+		}
+
+		let len = cmp::min(buf.len(), self.to_read.len());
+		buf[..len].copy_from_slice(&self.to_read[..len]);
+		self.to_read.drain(..len);
+		Poll::Ready(Ok(len))
+	}
+}
+
+impl AsyncWrite for MockSocket {
+	fn poll_write(self: Pin<&mut Self>, _: &mut Context, buf: &[u8]) -> Poll<Result<usize, io::Error>> {
+		if self.stalled_writes {
+			return Poll::Pending;
+		}
+		self.written.lock().unwrap().extend_from_slice(buf);
+		Poll::Ready(Ok(buf.len()))
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<(), io::Error>> {
+		if self.stalled_writes {
+			return Poll::Pending;
+		}
+		Poll::Ready(Ok(()))
+	}
+
+	fn poll_close(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<(), io::Error>> {
+		if self.stalled_writes {
+			return Poll::Pending;
+		}
+		Poll::Ready(Ok(()))
+	}
+}
+
+fn build_proto() -> NotifsInHandlerProto {
+	NotifsInHandlerProto::new(
+		PROTO_NAME,
+		Vec::new(),
+		DEFAULT_MAX_HANDSHAKE_SIZE,
+		1024,
+		Duration::from_secs(20),
+	)
+}
+
+fn build_handler() -> NotifsInHandler<MockSocket> {
+	build_proto().build_handler()
+}
+
+/// Opens a substream on which the remote sends an empty handshake followed with `frames`.
+fn open_substream(handler: &mut NotifsInHandler<MockSocket>, frames: &[&[u8]]) {
+	open_substream_with_name(handler, PROTO_NAME, frames)
+}
+
+/// Same as `open_substream`, but negotiates the given protocol name.
+fn open_substream_with_name(
+	handler: &mut NotifsInHandler<MockSocket>,
+	protocol_name: &'static [u8],
+	frames: &[&[u8]]
+) {
+	open_mock_substream(handler, protocol_name, frames, MockEnd::Pending)
+}
+
+/// Same as `open_substream`, but the remote closes the substream after `frames`.
+fn open_closing_substream(handler: &mut NotifsInHandler<MockSocket>, frames: &[&[u8]]) {
+	open_mock_substream(handler, PROTO_NAME, frames, MockEnd::Eof)
+}
+
+/// Same as `open_substream`, but reading fails with `error` after `frames`.
+fn open_failing_substream(
+	handler: &mut NotifsInHandler<MockSocket>,
+	frames: &[&[u8]],
+	error: io::ErrorKind
+) {
+	open_mock_substream(handler, PROTO_NAME, frames, MockEnd::Error(error))
+}
+
+fn open_mock_substream(
+	handler: &mut NotifsInHandler<MockSocket>,
+	protocol_name: &'static [u8],
+	frames: &[&[u8]],
+	end: MockEnd
+) {
+	let (msg, substream) = mock_substream(&handler.in_protocol, protocol_name, frames, end);
+	handler.inject_substream(msg, substream);
+}
+
+/// Same as `open_substream`, but reading returns `Pending` `pending_reads` times after
+/// `frames`, then behaves according to `end`.
+fn open_interrupted_substream(
+	handler: &mut NotifsInHandler<MockSocket>,
+	frames: &[&[u8]],
+	pending_reads: usize,
+	end: MockEnd
+) {
+	let mut socket = mock_socket(frames, end);
+	socket.pending_reads = pending_reads;
+	let upgrade = handler.in_protocol.clone().upgrade_inbound(socket, Cow::Borrowed(PROTO_NAME));
+	let (msg, substream) = executor::block_on(upgrade).unwrap();
+	handler.inject_substream(msg, substream);
+}
+
+/// Same as `open_closing_substream` without frames, but returns a buffer containing
+/// everything written on the substream.
+fn open_recording_substream(handler: &mut NotifsInHandler<MockSocket>) -> Arc<Mutex<Vec<u8>>> {
+	let socket = mock_socket(&[], MockEnd::Eof);
+	let written = socket.written.clone();
+	let upgrade = handler.in_protocol.clone().upgrade_inbound(socket, Cow::Borrowed(PROTO_NAME));
+	let (msg, substream) = executor::block_on(upgrade).unwrap();
+	handler.inject_substream(msg, substream);
+	written
+}
+
+/// Negotiates a substream on which the remote sends an empty handshake followed with
+/// `frames`, then behaves according to `end`.
+fn mock_substream(
+	in_protocol: &NotificationsIn,
+	protocol_name: &'static [u8],
+	frames: &[&[u8]],
+	end: MockEnd
+) -> (Vec<u8>, NotificationsInSubstream<MockSocket>) {
+	let upgrade = in_protocol.clone()
+		.upgrade_inbound(mock_socket(frames, end), Cow::Borrowed(protocol_name));
+	executor::block_on(upgrade).unwrap()
+}
+
+/// Builds a socket on which the remote sends an empty handshake followed with `frames`.
+fn mock_socket(frames: &[&[u8]], end: MockEnd) -> MockSocket {
+	let mut to_read = vec![0];
+	for frame in frames {
+		assert!(frame.len() < 128);
+		to_read.push(frame.len() as u8);
+		to_read.extend_from_slice(frame);
+	}
+
+	MockSocket { to_read, pending_reads: 0, end, written: Default::default(), stalled_writes: false }
+}
+
+fn next_event(
+	handler: &mut NotifsInHandler<MockSocket>
+) -> Poll<ProtocolsHandlerEvent<DeniedUpgrade, (), NotifsInHandlerOut, void::Void>> {
+	let waker = task::noop_waker();
+	handler.poll_event(&mut Context::from_waker(&waker))
+}
+
+/// Polls the handler, which must produce an event.
+fn expect_any_event(handler: &mut NotifsInHandler<MockSocket>) -> NotifsInHandlerOut {
+	match next_event(handler) {
+		Poll::Ready(ProtocolsHandlerEvent::Custom(event)) => event,
+		Poll::Ready(_) => panic!("unexpected event"),
+		Poll::Pending => panic!("expected an event"),
+	}
+}
+
+/// Polls the handler, whose event must match `$pat`, then evaluates `$body`, if any, with the
+/// bindings of the pattern.
+macro_rules! expect_event {
+	($handler:expr, $pat:pat => $body:expr) => {
+		match expect_any_event($handler) {
+			$pat => $body,
+			other => panic!("expected {}, got {:?}", stringify!($pat), other),
+		}
+	};
+	($handler:expr, $pat:pat) => {
+		expect_event!($handler, $pat => ())
+	};
+}
+
+/// Polls the handler, which must not produce any event.
+fn expect_pending(handler: &mut NotifsInHandler<MockSocket>) {
+	if let Poll::Ready(event) = next_event(handler) {
+		panic!("expected no event, got {:?}", event);
+	}
+}
+
+/// Polls the handler, which must produce a notification received on the given substream.
+fn expect_notif(handler: &mut NotifsInHandler<MockSocket>, generation: u64, expected: &[u8]) {
+	expect_event!(handler, NotifsInHandlerOut::Notif { generation: g, message } => {
+		assert_eq!(g, generation);
+		assert_eq!(&message[..], expected);
+	});
+}
+
+/// Polls the handler, which must report the substream with the given generation, and accepts
+/// it with an empty handshake.
+fn accept(handler: &mut NotifsInHandler<MockSocket>, generation: u64) {
+	expect_event!(handler, NotifsInHandlerOut::OpenRequest { generation: g, .. } =>
+		assert_eq!(g, generation));
+	handler.handle_event(NotifsInHandlerIn::Accept { generation, handshake: Vec::new() });
+}
+
+/// Waker that counts the number of times it has been woken up.
+struct CountingWaker(AtomicUsize);
+
+impl ArcWake for CountingWaker {
+	fn wake_by_ref(arc_self: &Arc<Self>) {
+		arc_self.0.fetch_add(1, Ordering::SeqCst);
+	}
+}
+
+/// Maximum time to wait for the handler to wake up the task. Only reached if the handler
+/// fails to do so, so it can be generous without slowing the tests down.
+const WAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Waker that sends a message on a channel every time it is woken up.
+struct ChannelWaker(Mutex<mpsc::Sender<()>>);
+
+impl ArcWake for ChannelWaker {
+	fn wake_by_ref(arc_self: &Arc<Self>) {
+		let _ = arc_self.0.lock().unwrap().send(());
+	}
+}
+
+fn channel_waker() -> (task::Waker, mpsc::Receiver<()>) {
+	let (tx, rx) = mpsc::channel();
+	(task::waker(Arc::new(ChannelWaker(Mutex::new(tx)))), rx)
+}
+
+/// Polls the handler until it produces an event, waiting for it to wake up the task in
+/// between, for example once one of its timers has fired.
+fn wait_event(handler: &mut NotifsInHandler<MockSocket>) -> NotifsInHandlerOut {
+	let (waker, wakes) = channel_waker();
+	let mut cx = Context::from_waker(&waker);
+	loop {
+		match handler.poll_event(&mut cx) {
+			Poll::Ready(ProtocolsHandlerEvent::Custom(event)) => return event,
+			Poll::Ready(_) => panic!("unexpected event"),
+			Poll::Pending => wakes.recv_timeout(WAKE_TIMEOUT)
+				.expect("the handler never woke up the task"),
+		}
+	}
+}
+
+/// Polls the handler, which must not produce any event, and waits for it to wake up the task.
+fn wait_wake(handler: &mut NotifsInHandler<MockSocket>) {
+	let (waker, wakes) = channel_waker();
+	assert!(handler.poll_event(&mut Context::from_waker(&waker)).is_pending());
+	wakes.recv_timeout(WAKE_TIMEOUT).expect("the handler never woke up the task");
+}
+
+#[test]
+fn queued_events_delivered_without_external_wake() {
+	let mut handler = build_proto()
+		.into_handler(&PeerId::random(), &ConnectedPoint::Dialer {
+			address: "/memory/0".parse().unwrap(),
+		});
+
+	for _ in 0..3 {
+		let event = NotifsInHandlerOut::Closed {
+			generation: 0,
+			reason: NotifsInClosedReason::Remote,
+		};
+		handler.events_queue.push_back(ProtocolsHandlerEvent::Custom(event));
+	}
+
+	let counter = Arc::new(CountingWaker(AtomicUsize::new(0)));
+	let waker = task::waker(counter.clone());
+	let mut cx = Context::from_waker(&waker);
+
+	for _ in 0..3 {
+		match handler.poll(&mut cx) {
+			Poll::Ready(ProtocolsHandlerEvent::Custom(NotifsInHandlerOut::Closed { .. })) => {},
+			_ => panic!("expected a queued event"),
+		}
+	}
+
+	// We have been woken up after each event except the last one.
+	assert_eq!(counter.0.load(Ordering::SeqCst), 2);
+	assert!(handler.poll(&mut cx).is_pending());
+	assert_eq!(counter.0.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn pause_and_resume() {
+	let mut handler = build_handler();
+	open_substream(&mut handler, &[&b"foo"[..], &b"bar"[..], &b"baz"[..]]);
+
+	expect_event!(&mut handler, NotifsInHandlerOut::OpenRequest { handshake, .. } =>
+		assert!(handshake.is_empty()));
+	handler.handle_event(NotifsInHandlerIn::Accept { generation: 0, handshake: Vec::new() });
+
+	handler.handle_event(NotifsInHandlerIn::Pause);
+	expect_pending(&mut handler);
+	assert!(handler.keep_alive().is_yes());
+
+	for expected in &[&b"foo"[..], &b"bar"[..], &b"baz"[..]] {
+		handler.handle_event(NotifsInHandlerIn::Resume);
+		expect_notif(&mut handler, 0, *expected);
+
+		handler.handle_event(NotifsInHandlerIn::Pause);
+		expect_pending(&mut handler);
+		assert!(handler.keep_alive().is_yes());
+	}
+
+	handler.handle_event(NotifsInHandlerIn::Resume);
+	expect_pending(&mut handler);
+}
+
+#[test]
+fn handshake_flushed_while_paused() {
+	let mut handler = build_handler();
+	let written = open_recording_substream(&mut handler);
+	let _ = next_event(&mut handler);
+	handler.handle_event(NotifsInHandlerIn::Accept { generation: 0, handshake: b"foo".to_vec() });
+	handler.handle_event(NotifsInHandlerIn::Pause);
+
+	expect_pending(&mut handler);
+	assert_eq!(&written.lock().unwrap()[..], b"\x03foo");
+	// Nothing is read, so the remote closing the substream is only noticed after resuming.
+	assert_eq!(handler.state(), NotifsInState::Open);
+
+	handler.handle_event(NotifsInHandlerIn::Resume);
+	expect_event!(&mut handler, NotifsInHandlerOut::Closed { generation: 0, reason } =>
+		assert_eq!(reason, NotifsInClosedReason::Remote));
+}
+
+#[test]
+fn pause_without_substream_is_noop() {
+	let mut handler = build_handler();
+	handler.handle_event(NotifsInHandlerIn::Pause);
+	assert!(!handler.paused);
+
+	open_substream(&mut handler, &[&b"foo"[..]]);
+	accept(&mut handler, 0);
+
+	expect_notif(&mut handler, 0, b"foo");
+}
+
+#[test]
+fn token_bucket_throttles_messages() {
+	let limit = NotifsInRateLimit {
+		messages_per_sec: 4,
+		bytes_per_sec: 1024 * 1024,
+		max_saturation: Duration::from_secs(10),
+	};
+	let start = Instant::now();
+	let mut bucket = TokenBucket::new(limit, start);
+
+	// The initial burst goes through without any delay.
+	for _ in 0..4 {
+		assert_eq!(bucket.check(start), RateLimitCheck::Allowed);
+		bucket.consume(10, start);
+	}
+
+	assert_eq!(bucket.check(start), RateLimitCheck::Throttled(Duration::from_millis(250)));
+	let later = start + Duration::from_millis(125);
+	assert_eq!(bucket.check(later), RateLimitCheck::Throttled(Duration::from_millis(125)));
+	let later = start + Duration::from_millis(250);
+	assert_eq!(bucket.check(later), RateLimitCheck::Allowed);
+}
+
+#[test]
+fn token_bucket_throttles_bytes() {
+	let limit = NotifsInRateLimit {
+		messages_per_sec: 1000,
+		bytes_per_sec: 512,
+		max_saturation: Duration::from_secs(10),
+	};
+	let start = Instant::now();
+	let mut bucket = TokenBucket::new(limit, start);
+
+	assert_eq!(bucket.check(start), RateLimitCheck::Allowed);
+	bucket.consume(1024, start);
+	assert_eq!(bucket.check(start), RateLimitCheck::Throttled(Duration::from_secs(1)));
+	let later = start + Duration::from_secs(1);
+	assert_eq!(bucket.check(later), RateLimitCheck::Allowed);
+}
+
+#[test]
+fn token_bucket_refill_is_capped() {
+	let limit = NotifsInRateLimit {
+		messages_per_sec: 2,
+		bytes_per_sec: 1024,
+		max_saturation: Duration::from_secs(10),
+	};
+	let start = Instant::now();
+	let mut bucket = TokenBucket::new(limit, start);
+
+	// Staying quiet for a long time doesn't allow a burst larger than the limit.
+	let later = start + Duration::from_secs(3600);
+	for _ in 0..2 {
+		assert_eq!(bucket.check(later), RateLimitCheck::Allowed);
+		bucket.consume(1, later);
+	}
+	assert_eq!(bucket.check(later), RateLimitCheck::Throttled(Duration::from_millis(500)));
+}
+
+#[test]
+fn token_bucket_saturation() {
+	let limit = NotifsInRateLimit {
+		messages_per_sec: 1,
+		bytes_per_sec: 1024,
+		max_saturation: Duration::from_secs(5),
+	};
+	let start = Instant::now();
+	let mut bucket = TokenBucket::new(limit, start);
+
+	// The remote sends one message per second, which keeps it saturated.
+	for n in 0..5 {
+		let now = start + Duration::from_secs(n);
+		assert_eq!(bucket.check(now), RateLimitCheck::Allowed);
+		bucket.consume(1, now);
+		assert_eq!(bucket.check(now), RateLimitCheck::Throttled(Duration::from_secs(1)));
+	}
+
+	let now = start + Duration::from_secs(5);
+	assert_eq!(bucket.check(now), RateLimitCheck::Allowed);
+	bucket.consume(1, now);
+	assert_eq!(bucket.check(now), RateLimitCheck::Exceeded);
+
+	// Going idle resets the saturation.
+	bucket.idle();
+	assert_eq!(bucket.check(now), RateLimitCheck::Throttled(Duration::from_secs(1)));
+}
+
+#[test]
+fn rate_limit_stops_reading() {
+	let mut handler = build_proto()
+		.with_rate_limit(NotifsInRateLimit {
+			messages_per_sec: 2,
+			bytes_per_sec: 1024,
+			max_saturation: Duration::from_secs(60),
+		})
+		.build_handler();
+	open_substream(&mut handler, &[&b"foo"[..], &b"bar"[..], &b"baz"[..]]);
+
+	accept(&mut handler, 0);
+
+	for expected in &[&b"foo"[..], &b"bar"[..]] {
+		expect_notif(&mut handler, 0, *expected);
+	}
+
+	// The third notification is ready but the bucket is empty.
+	expect_pending(&mut handler);
+	assert!(handler.rate_limit_delay.is_some());
+	assert!(handler.keep_alive().is_yes());
+}
+
+#[test]
+fn keep_alive_grace_period() {
+	let mut handler = build_proto()
+		.with_keep_alive_grace(Duration::from_secs(60))
+		.build_handler();
+	assert!(handler.keep_alive() == KeepAlive::No);
+
+	open_substream(&mut handler, &[]);
+	assert!(handler.keep_alive().is_yes());
+
+	handler.handle_event(NotifsInHandlerIn::Refuse { generation: 0 });
+	match handler.keep_alive() {
+		KeepAlive::Until(until) => assert!(until > Instant::now() + Duration::from_secs(30)),
+		_ => panic!("expected the grace period to be active"),
+	}
+
+	// Once the grace period is over, the connection is no longer kept alive.
+	handler.keep_alive_until = Some(Instant::now());
+	assert!(handler.keep_alive() == KeepAlive::No);
+}
+
+#[test]
+fn no_keep_alive_grace_period_by_default() {
+	let mut handler = build_handler();
+	open_substream(&mut handler, &[]);
+	assert!(handler.keep_alive().is_yes());
+
+	handler.handle_event(NotifsInHandlerIn::Refuse { generation: 0 });
+	assert!(handler.keep_alive() == KeepAlive::No);
+}
+
+#[test]
+fn open_request_reports_fallback_name() {
+	const FALLBACK_PROTO_NAME: &'static [u8] = b"/test/proto/0";
+
+	let mut handler = build_proto()
+		.with_fallback_names(vec![FALLBACK_PROTO_NAME])
+		.build_handler::<MockSocket>();
+	assert_eq!(handler.protocol_name(), PROTO_NAME);
+	assert_eq!(
+		handler.protocol_names().collect::<Vec<_>>(),
+		vec![PROTO_NAME, FALLBACK_PROTO_NAME]
+	);
+
+	open_substream_with_name(&mut handler, FALLBACK_PROTO_NAME, &[]);
+	expect_event!(&mut handler, NotifsInHandlerOut::OpenRequest { protocol_name, .. } =>
+		assert_eq!(&protocol_name[..], FALLBACK_PROTO_NAME));
+}
+
+#[test]
+fn close_after_accept() {
+	let mut handler = build_handler();
+	open_substream(&mut handler, &[&b"foo"[..]]);
+	expect_event!(&mut handler, NotifsInHandlerOut::OpenRequest { .. });
+
+	handler.handle_event(NotifsInHandlerIn::Accept { generation: 0, handshake: b"hello".to_vec() });
+	handler.handle_event(NotifsInHandlerIn::Close);
+	assert!(handler.substream_mut().is_none());
+	assert!(handler.keep_alive().is_yes());
+
+	expect_event!(&mut handler, NotifsInHandlerOut::Closed { .. });
+	assert!(handler.keep_alive() == KeepAlive::No);
+	expect_pending(&mut handler);
+}
+
+#[test]
+fn close_counts_as_refusal() {
+	let mut handler = build_handler();
+	open_substream(&mut handler, &[]);
+	expect_event!(&mut handler, NotifsInHandlerOut::OpenRequest { .. });
+	assert_eq!(handler.state(), NotifsInState::PendingAcceptRefuse);
+
+	handler.handle_event(NotifsInHandlerIn::Close);
+	assert_eq!(handler.state(), NotifsInState::Closing);
+
+	expect_event!(&mut handler, NotifsInHandlerOut::Closed { generation: 0, reason } =>
+		assert_eq!(reason, NotifsInClosedReason::Local));
+
+	// A new substream is handled normally afterwards, and a late answer concerning the first
+	// one is ignored.
+	open_substream(&mut handler, &[&b"foo"[..]]);
+	expect_event!(&mut handler, NotifsInHandlerOut::OpenRequest { generation: 1, .. });
+	handler.handle_event(NotifsInHandlerIn::Refuse { generation: 0 });
+	assert_eq!(handler.state(), NotifsInState::PendingAcceptRefuse);
+	handler.handle_event(NotifsInHandlerIn::Accept { generation: 1, handshake: Vec::new() });
+	expect_notif(&mut handler, 1, b"foo");
+}
+
+#[test]
+fn close_without_substream_is_noop() {
+	let mut handler = build_handler();
+	handler.handle_event(NotifsInHandlerIn::Close);
+	expect_pending(&mut handler);
+
+	open_substream(&mut handler, &[]);
+	let _ = next_event(&mut handler);
+	handler.handle_event(NotifsInHandlerIn::Close);
+	handler.handle_event(NotifsInHandlerIn::Close);
+	assert_eq!(handler.state(), NotifsInState::Closing);
+
+	expect_event!(&mut handler, NotifsInHandlerOut::Closed { .. });
+	expect_pending(&mut handler);
+}
+
+#[test]
+fn close_twice_before_closed() {
+	let mut handler = build_handler();
+	open_substream(&mut handler, &[]);
+	accept(&mut handler, 0);
+	handler.handle_event(NotifsInHandlerIn::Close);
+
+	// The remote opens a new substream, which is closed as well before the handler is polled.
+	open_substream(&mut handler, &[]);
+	handler.handle_event(NotifsInHandlerIn::Close);
+	assert_eq!(handler.closing_substreams.len(), 2);
+	assert_eq!(handler.state(), NotifsInState::Closing);
+
+	expect_event!(&mut handler, NotifsInHandlerOut::OpenRequest { generation: 1, .. });
+	for expected in 0..2 {
+		expect_event!(&mut handler, NotifsInHandlerOut::Closed { generation, reason } => {
+			assert_eq!(generation, expected);
+			assert_eq!(reason, NotifsInClosedReason::Local);
+		});
+	}
+	expect_pending(&mut handler);
+	assert_eq!(handler.state(), NotifsInState::Closed);
+	assert!(handler.keep_alive() == KeepAlive::No);
+}
+
+#[test]
+fn duplicate_substream_dropped() {
+	let mut handler = build_handler();
+	open_substream(&mut handler, &[]);
+	open_substream(&mut handler, &[]);
+
+	expect_event!(&mut handler, NotifsInHandlerOut::OpenRequest { .. });
+	expect_pending(&mut handler);
+	match handler.state {
+		State::PendingAcceptRefuse { generation: 0, .. } => {},
+		_ => panic!("expected to wait for an answer"),
+	}
+}
+
+#[test]
+fn duplicate_substream_reported() {
+	let mut handler = build_proto()
+		.with_duplicate_policy(NotifsInDuplicatePolicy::RejectAndReport)
+		.build_handler();
+	open_substream(&mut handler, &[]);
+	open_substream(&mut handler, &[]);
+
+	expect_event!(&mut handler, NotifsInHandlerOut::OpenRequest { generation: 0, .. });
+	expect_event!(&mut handler, NotifsInHandlerOut::DuplicateSubstream { generation: 0 });
+	expect_pending(&mut handler);
+	match handler.state {
+		State::PendingAcceptRefuse { generation: 0, .. } => {},
+		_ => panic!("expected to wait for an answer"),
+	}
+}
+
+#[test]
+fn duplicate_substream_replaces_pending_one() {
+	// The remote opens a second substream before the first `OpenRequest` has been answered,
+	// and the answer concerning the first substream arrives afterwards.
+	let mut handler = build_proto()
+		.with_duplicate_policy(NotifsInDuplicatePolicy::ReplaceOld)
+		.build_handler();
+	open_substream(&mut handler, &[&b"foo"[..]]);
+	open_substream(&mut handler, &[&b"bar"[..]]);
+
+	expect_event!(&mut handler, NotifsInHandlerOut::OpenRequest { generation: 0, .. });
+	expect_event!(&mut handler, NotifsInHandlerOut::Closed { generation: 0, reason } =>
+		assert_eq!(reason, NotifsInClosedReason::Replaced));
+	expect_event!(&mut handler, NotifsInHandlerOut::OpenRequest { generation: 1, .. });
+	assert_eq!(handler.stats().open_requests_refused, 1);
+
+	// The late answer concerning the first substream is ignored.
+	handler.handle_event(NotifsInHandlerIn::Accept { generation: 0, handshake: Vec::new() });
+	assert_eq!(handler.state(), NotifsInState::PendingAcceptRefuse);
+	expect_pending(&mut handler);
+
+	handler.handle_event(NotifsInHandlerIn::Accept { generation: 1, handshake: Vec::new() });
+	expect_notif(&mut handler, 1, b"bar");
+	assert_eq!(handler.stats().open_requests_accepted, 1);
+}
+
+#[test]
+fn duplicate_substream_replaces_open_one() {
+	let mut handler = build_proto()
+		.with_duplicate_policy(NotifsInDuplicatePolicy::ReplaceOld)
+		.build_handler();
+	open_substream(&mut handler, &[&b"foo"[..]]);
+	accept(&mut handler, 0);
+	expect_notif(&mut handler, 0, b"foo");
+
+	open_substream(&mut handler, &[&b"bar"[..]]);
+	expect_event!(&mut handler, NotifsInHandlerOut::Closed { generation: 0, reason } =>
+		assert_eq!(reason, NotifsInClosedReason::Replaced));
+	expect_event!(&mut handler, NotifsInHandlerOut::OpenRequest { generation: 1, .. });
+	assert_eq!(handler.stats().open_requests_refused, 0);
+
+	// Answers concerning the first substream are ignored.
+	handler.handle_event(NotifsInHandlerIn::Refuse { generation: 0 });
+	assert_eq!(handler.state(), NotifsInState::PendingAcceptRefuse);
+	handler.handle_event(NotifsInHandlerIn::Accept { generation: 1, handshake: Vec::new() });
+	expect_notif(&mut handler, 1, b"bar");
+}
+
+#[test]
+fn open_close_open_after_accept() {
+	let mut handler = build_handler();
+	open_closing_substream(&mut handler, &[&b"foo"[..]]);
+	accept(&mut handler, 0);
+
+	expect_notif(&mut handler, 0, b"foo");
+	expect_event!(&mut handler, NotifsInHandlerOut::Closed { generation: 0, reason } =>
+		assert_eq!(reason, NotifsInClosedReason::Remote));
+	assert_eq!(handler.state(), NotifsInState::Closed);
+
+	open_substream(&mut handler, &[&b"bar"[..]]);
+	accept(&mut handler, 1);
+	expect_notif(&mut handler, 1, b"bar");
+}
+
+#[test]
+fn open_timeout_open_ignores_obsolete_answer() {
+	let proto = NotifsInHandlerProto::new(
+		PROTO_NAME,
+		Vec::new(),
+		DEFAULT_MAX_HANDSHAKE_SIZE,
+		1024,
+		Duration::from_millis(1),
+	);
+	let mut handler = proto.build_handler();
+
+	// The first substream isn't answered in time.
+	open_substream(&mut handler, &[]);
+	let _ = next_event(&mut handler);
+	match wait_event(&mut handler) {
+		NotifsInHandlerOut::RefusedByTimeout { generation: 0 } => {},
+		other => panic!("expected RefusedByTimeout, got {:?}", other),
+	}
+
+	// A second substream is opened before the late answer to the first one arrives.
+	open_substream(&mut handler, &[&b"foo"[..]]);
+	let _ = next_event(&mut handler);
+	match handler.state {
+		State::PendingAcceptRefuse { generation: 1, .. } => {},
+		_ => panic!("expected to wait for an answer"),
+	}
+
+	// The late refusal of the first substream doesn't affect the second one.
+	handler.handle_event(NotifsInHandlerIn::Refuse { generation: 0 });
+	assert_eq!(handler.state(), NotifsInState::PendingAcceptRefuse);
+
+	handler.handle_event(NotifsInHandlerIn::Accept { generation: 1, handshake: Vec::new() });
+	expect_notif(&mut handler, 1, b"foo");
+}
+
+#[test]
+fn state_follows_substream_lifecycle() {
+	let mut handler = build_handler();
+	assert_eq!(handler.state(), NotifsInState::Closed);
+
+	open_substream(&mut handler, &[]);
+	let _ = next_event(&mut handler);
+	assert_eq!(handler.state(), NotifsInState::PendingAcceptRefuse);
+	assert!(!handler.is_open());
+
+	handler.handle_event(NotifsInHandlerIn::Accept { generation: 0, handshake: Vec::new() });
+	assert_eq!(handler.state(), NotifsInState::Open);
+	assert!(handler.is_open());
+
+	handler.handle_event(NotifsInHandlerIn::Close);
+	assert_eq!(handler.state(), NotifsInState::Closing);
+	assert!(!handler.is_open());
+
+	expect_event!(&mut handler, NotifsInHandlerOut::Closed { .. });
+	assert_eq!(handler.state(), NotifsInState::Closed);
+}
+
+#[test]
+fn stats_survive_reopening() {
+	let mut handler = build_handler();
+	assert_eq!(handler.stats(), NotifsInStats::default());
+
+	open_closing_substream(&mut handler, &[&b"foo"[..], &b"ba"[..]]);
+	accept(&mut handler, 0);
+	for _ in 0..3 {
+		assert!(next_event(&mut handler).is_ready());
+	}
+	assert_eq!(handler.state(), NotifsInState::Closed);
+
+	open_substream(&mut handler, &[&b"baz"[..]]);
+	let _ = next_event(&mut handler);
+	handler.handle_event(NotifsInHandlerIn::Refuse { generation: 1 });
+
+	let stats = handler.stats();
+	assert_eq!(stats.notifications_received, 2);
+	assert_eq!(stats.bytes_received, 5);
+	assert!(stats.last_notification.is_some());
+	assert_eq!(stats.open_requests_accepted, 1);
+	assert_eq!(stats.open_requests_refused, 1);
+}
+
+#[test]
+fn stats_reported_periodically() {
+	let mut handler = build_proto()
+		.with_stats_interval(Duration::from_millis(10))
+		.build_handler();
+	open_substream(&mut handler, &[&b"foo"[..]]);
+	handler.handle_event(NotifsInHandlerIn::Accept { generation: 0, handshake: Vec::new() });
+
+	// Only the timer can wake us up once the notification has been read. Reports sent
+	// before that are ignored.
+	let mut reports = 0;
+	while reports < 2 {
+		match wait_event(&mut handler) {
+			NotifsInHandlerOut::Stats(stats) => if stats.notifications_received == 1 {
+				reports += 1;
+			},
+			NotifsInHandlerOut::OpenRequest { .. } | NotifsInHandlerOut::Notif { .. } => {},
+			other => panic!("expected Stats, got {:?}", other),
+		}
+	}
+}
+
+#[test]
+fn metrics_shared_between_handlers() {
+	let registry = Registry::new();
+	let mut metrics = NotifsInMetrics::register(&registry).unwrap();
+	metrics.add_protocol(Cow::Borrowed(PROTO_NAME));
+	assert!(metrics.protocol(b"/test/other/1").is_none());
+
+	let mut handlers = (0..2).map(|_| {
+		build_proto()
+			.with_metrics(metrics.protocol(PROTO_NAME).unwrap())
+			.build_handler::<MockSocket>()
+	}).collect::<Vec<_>>();
+
+	for handler in &mut handlers {
+		open_substream(handler, &[&b"foo"[..]]);
+		let _ = next_event(handler);
+	}
+	handlers[0].handle_event(NotifsInHandlerIn::Accept { generation: 0, handshake: Vec::new() });
+	handlers[1].handle_event(NotifsInHandlerIn::Refuse { generation: 0 });
+	let _ = next_event(&mut handlers[0]);
+
+	let protocol_metrics = metrics.protocol(PROTO_NAME).unwrap();
+	assert_eq!(protocol_metrics.notifications_received.get(), 1);
+	assert_eq!(protocol_metrics.bytes_received.get(), 3);
+	assert_eq!(protocol_metrics.open_requests_refused.get(), 1);
+	assert_eq!(registry.gather().len(), 3);
+}
+
+fn build_batching_handler(max_messages: usize, max_bytes: usize) -> NotifsInHandler<MockSocket> {
+	build_proto()
+		.with_batching(NotifsInBatching { max_messages, max_bytes })
+		.build_handler()
+}
+
+#[test]
+fn batching_limits_messages() {
+	let mut handler = build_batching_handler(2, 1024);
+	open_substream(&mut handler, &[&b"a"[..], &b"b"[..], &b"c"[..]]);
+	accept(&mut handler, 0);
+
+	expect_event!(&mut handler, NotifsInHandlerOut::NotifBatch { messages: batch, .. } =>
+		assert_eq!(batch, vec![&b"a"[..], &b"b"[..]]));
+	expect_event!(&mut handler, NotifsInHandlerOut::NotifBatch { messages: batch, .. } =>
+		assert_eq!(batch, vec![&b"c"[..]]));
+	expect_pending(&mut handler);
+}
+
+#[test]
+fn batching_limits_bytes() {
+	let mut handler = build_batching_handler(10, 4);
+	open_substream(&mut handler, &[&b"foo"[..], &b"bar"[..], &b"baz"[..]]);
+	accept(&mut handler, 0);
+
+	for expected in &[&[&b"foo"[..], &b"bar"[..]][..], &[&b"baz"[..]][..]] {
+		expect_event!(&mut handler, NotifsInHandlerOut::NotifBatch { messages: batch, .. } =>
+			assert_eq!(batch, expected.to_vec()));
+	}
+}
+
+#[test]
+fn batching_delivers_notifications_before_close() {
+	let mut handler = build_batching_handler(10, 1024);
+	open_closing_substream(&mut handler, &[&b"foo"[..], &b"bar"[..]]);
+	accept(&mut handler, 0);
+
+	expect_event!(&mut handler, NotifsInHandlerOut::NotifBatch { messages: batch, .. } =>
+		assert_eq!(batch, vec![&b"foo"[..], &b"bar"[..]]));
+	expect_event!(&mut handler, NotifsInHandlerOut::Closed { .. });
+}
+
+#[test]
+fn read_error_reported() {
+	let mut handler = build_handler();
+	open_failing_substream(&mut handler, &[&b"foo"[..]], io::ErrorKind::ConnectionReset);
+	accept(&mut handler, 0);
+
+	expect_event!(&mut handler, NotifsInHandlerOut::Notif { .. });
+	expect_event!(&mut handler, NotifsInHandlerOut::Error {
+		generation: 0,
+		error: err @ NotifsInError::Io(_),
+	} => assert_eq!(err.io_error_kind(), io::ErrorKind::ConnectionReset));
+	assert_eq!(handler.state(), NotifsInState::Closed);
+	expect_pending(&mut handler);
+}
+
+#[test]
+fn decode_error_reported() {
+	let mut handler = build_handler();
+	open_failing_substream(&mut handler, &[], io::ErrorKind::InvalidData);
+	accept(&mut handler, 0);
+
+	expect_event!(&mut handler, NotifsInHandlerOut::Error {
+		generation: 0,
+		error: NotifsInError::Codec(_),
+	});
+}
+
+#[test]
+fn clean_close_is_not_an_error() {
+	let mut handler = build_handler();
+	open_closing_substream(&mut handler, &[]);
+	accept(&mut handler, 0);
+
+	expect_event!(&mut handler, NotifsInHandlerOut::Closed { .. });
+}
+
+#[test]
+fn flapping_detector_ignores_reconnect() {
+	let config = NotifsInFlapping { window: Duration::from_secs(10), max_opens: 3 };
+	let start = Instant::now();
+	let mut detector = FlappingDetector::new(config);
+
+	for n in 0..3 {
+		assert_eq!(detector.record_open(start + Duration::from_secs(n)), None);
+	}
+}
+
+#[test]
+fn flapping_detector_reports_once_per_window() {
+	let config = NotifsInFlapping { window: Duration::from_secs(10), max_opens: 3 };
+	let start = Instant::now();
+	let mut detector = FlappingDetector::new(config);
+
+	for _ in 0..3 {
+		assert_eq!(detector.record_open(start), None);
+	}
+	assert_eq!(detector.record_open(start + Duration::from_secs(1)), Some(4));
+	assert_eq!(detector.record_open(start + Duration::from_secs(2)), None);
+	assert_eq!(detector.record_open(start + Duration::from_secs(9)), None);
+
+	// The openings at `start` have left the window.
+	assert_eq!(detector.record_open(start + Duration::from_secs(11)), Some(4));
+}
+
+#[test]
+fn flapping_detector_decays() {
+	let config = NotifsInFlapping { window: Duration::from_secs(10), max_opens: 3 };
+	let start = Instant::now();
+	let mut detector = FlappingDetector::new(config);
+
+	// One opening every 4 seconds never reaches the threshold.
+	for n in 0..20 {
+		assert_eq!(detector.record_open(start + Duration::from_secs(n * 4)), None);
+	}
+}
+
+#[test]
+fn flapping_reported_by_handler() {
+	let mut handler = build_proto()
+		.with_flapping_detection(NotifsInFlapping { window: Duration::from_secs(60), max_opens: 1 })
+		.build_handler();
+
+	open_substream(&mut handler, &[]);
+	let _ = next_event(&mut handler);
+	handler.handle_event(NotifsInHandlerIn::Close);
+	let _ = next_event(&mut handler);
+
+	open_substream(&mut handler, &[]);
+	expect_event!(&mut handler, NotifsInHandlerOut::Flapping { opens_in_window: 2 });
+	expect_event!(&mut handler, NotifsInHandlerOut::OpenRequest { .. });
+}
+
+#[test]
+fn answer_to_unknown_generation_ignored() {
+	let mut handler = build_handler();
+	open_substream(&mut handler, &[]);
+	let _ = next_event(&mut handler);
+
+	handler.handle_event(NotifsInHandlerIn::Accept { generation: 1, handshake: Vec::new() });
+	assert_eq!(handler.state(), NotifsInState::PendingAcceptRefuse);
+}
+
+#[test]
+fn refuse_with_reason() {
+	let mut handler = build_handler();
+	open_substream(&mut handler, &[]);
+	let _ = next_event(&mut handler);
+
+	handler.handle_event(NotifsInHandlerIn::RefuseWithReason {
+		generation: 0,
+		reason: b"wrong genesis".to_vec(),
+	});
+	assert_eq!(handler.state(), NotifsInState::Closed);
+	assert_eq!(handler.refusing_substreams.len(), 1);
+	assert!(handler.keep_alive().is_yes());
+
+	// The reason is sent without any event being emitted.
+	expect_pending(&mut handler);
+	assert!(handler.refusing_substreams.is_empty());
+	assert_eq!(handler.stats().open_requests_refused, 1);
+}
+
+#[test]
+fn poll_budget_yields() {
+	let mut handler = build_proto()
+		.with_poll_budget(2)
+		.build_handler();
+	let frames = [&b"a"[..], &b"b"[..], &b"c"[..], &b"d"[..], &b"e"[..]];
+	open_substream(&mut handler, &frames);
+	accept(&mut handler, 0);
+
+	let counter = Arc::new(CountingWaker(AtomicUsize::new(0)));
+	let waker = task::waker(counter.clone());
+	let mut cx = Context::from_waker(&waker);
+
+	let mut received = Vec::new();
+	let mut yields = 0;
+	while received.len() < frames.len() {
+		match handler.poll_event(&mut cx) {
+			Poll::Ready(ProtocolsHandlerEvent::Custom(NotifsInHandlerOut::Notif { message, .. })) =>
+				received.push(message),
+			Poll::Pending => {
+				// We must have been woken up in order to be polled again.
+				yields += 1;
+				assert_eq!(counter.0.load(Ordering::SeqCst), yields);
+			},
+			_ => panic!("unexpected event"),
+		}
+	}
+
+	assert_eq!(received, frames.to_vec());
+	assert_eq!(yields, 2);
+}
+
+#[test]
+fn woken_up_by_new_substream() {
+	let mut handler = build_handler();
+	let counter = Arc::new(CountingWaker(AtomicUsize::new(0)));
+	let waker = task::waker(counter.clone());
+	let mut cx = Context::from_waker(&waker);
+
+	assert!(handler.poll_event(&mut cx).is_pending());
+	assert_eq!(counter.0.load(Ordering::SeqCst), 0);
+
+	open_substream(&mut handler, &[&b"foo"[..]]);
+	assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+	match handler.poll_event(&mut cx) {
+		Poll::Ready(ProtocolsHandlerEvent::Custom(NotifsInHandlerOut::OpenRequest { .. })) => {},
+		_ => panic!("expected an OpenRequest"),
+	}
+
+	assert!(handler.poll_event(&mut cx).is_pending());
+	handler.handle_event(NotifsInHandlerIn::Accept { generation: 0, handshake: Vec::new() });
+	assert_eq!(counter.0.load(Ordering::SeqCst), 2);
+	match handler.poll_event(&mut cx) {
+		Poll::Ready(ProtocolsHandlerEvent::Custom(NotifsInHandlerOut::Notif { .. })) => {},
+		_ => panic!("expected a notification"),
+	}
+}
+
+fn build_multi_handler() -> NotifsInMultiHandler<MockSocket> {
+	NotifsInMultiHandlerProto::new(
+		vec![PROTO_NAME, OTHER_PROTO_NAME],
+		Vec::new(),
+		DEFAULT_MAX_HANDSHAKE_SIZE,
+		1024,
+		Duration::from_secs(20),
+	).build_handler()
+}
+
+/// Opens a substream for the protocol with the given index, on which the remote sends an
+/// empty handshake followed with `frames`.
+fn open_multi_substream(
+	handler: &mut NotifsInMultiHandler<MockSocket>,
+	protocol_index: usize,
+	frames: &[&[u8]]
+) {
+	let protocol_name = [PROTO_NAME, OTHER_PROTO_NAME][protocol_index];
+	let in_protocol = &handler.handlers[protocol_index].in_protocol;
+	let (msg, substream) = mock_substream(in_protocol, protocol_name, frames, MockEnd::Pending);
+	handler.inject_substream(protocol_index, msg, substream);
+}
+
+fn next_multi_event(
+	handler: &mut NotifsInMultiHandler<MockSocket>
+) -> Option<NotifsInMultiHandlerOut> {
+	let waker = task::noop_waker();
+	match handler.poll_event(&mut Context::from_waker(&waker)) {
+		Poll::Ready(ProtocolsHandlerEvent::Custom(event)) => Some(event),
+		Poll::Pending => None,
+		_ => panic!("unexpected event"),
+	}
+}
+
+#[test]
+fn multi_handler_tags_events_with_protocol() {
+	let mut handler = build_multi_handler();
+	assert_eq!(handler.num_protocols(), 2);
+
+	open_multi_substream(&mut handler, 1, &[&b"foo"[..]]);
+	match next_multi_event(&mut handler) {
+		Some(NotifsInMultiHandlerOut {
+			protocol_index: 1,
+			protocol_name,
+			event: NotifsInHandlerOut::OpenRequest { generation: 0, .. },
+		}) => assert_eq!(&protocol_name[..], OTHER_PROTO_NAME),
+		other => panic!("unexpected event: {:?}", other),
+	}
+	assert_eq!(handler.protocol(0).unwrap().state(), NotifsInState::Closed);
+
+	handler.handle_event(NotifsInMultiHandlerIn {
+		protocol_index: 1,
+		event: NotifsInHandlerIn::Accept { generation: 0, handshake: Vec::new() },
+	});
+	match next_multi_event(&mut handler) {
+		Some(NotifsInMultiHandlerOut {
+			protocol_index: 1,
+			event: NotifsInHandlerOut::Notif { generation: 0, message },
+			..
+		}) => assert_eq!(&message[..], b"foo"),
+		other => panic!("unexpected event: {:?}", other),
+	}
+	assert!(next_multi_event(&mut handler).is_none());
+}
+
+#[test]
+fn multi_handler_protocols_are_independent() {
+	let mut handler = build_multi_handler();
+	assert!(handler.keep_alive() == KeepAlive::No);
+
+	open_multi_substream(&mut handler, 0, &[&b"foo"[..]]);
+	open_multi_substream(&mut handler, 1, &[&b"bar"[..]]);
+	let mut opened = (0..2)
+		.map(|_| match next_multi_event(&mut handler) {
+			Some(NotifsInMultiHandlerOut {
+				protocol_index,
+				event: NotifsInHandlerOut::OpenRequest { .. },
+				..
+			}) => protocol_index,
+			other => panic!("unexpected event: {:?}", other),
+		})
+		.collect::<Vec<_>>();
+	opened.sort();
+	assert_eq!(opened, vec![0, 1]);
+
+	handler.handle_event(NotifsInMultiHandlerIn {
+		protocol_index: 0,
+		event: NotifsInHandlerIn::Accept { generation: 0, handshake: Vec::new() },
+	});
+	handler.handle_event(NotifsInMultiHandlerIn {
+		protocol_index: 1,
+		event: NotifsInHandlerIn::Refuse { generation: 0 },
+	});
+
+	match next_multi_event(&mut handler) {
+		Some(NotifsInMultiHandlerOut {
+			protocol_index: 0,
+			event: NotifsInHandlerOut::Notif { message, .. },
+			..
+		}) => assert_eq!(&message[..], b"foo"),
+		other => panic!("unexpected event: {:?}", other),
+	}
+	assert_eq!(handler.protocol(0).unwrap().state(), NotifsInState::Open);
+	assert_ne!(handler.protocol(1).unwrap().state(), NotifsInState::Open);
+	assert!(handler.keep_alive() == KeepAlive::Yes);
+}
+
+#[test]
+fn multi_handler_reports_upgrade_errors() {
+	let mut handler = build_multi_handler();
+	handler.inject_upgrade_error(1, ProtocolsHandlerUpgrErr::Timeout);
+	match next_multi_event(&mut handler) {
+		Some(NotifsInMultiHandlerOut {
+			protocol_index: 1,
+			protocol_name,
+			event: NotifsInHandlerOut::UpgradeError(ProtocolsHandlerUpgrErr::Timeout),
+		}) => assert_eq!(&protocol_name[..], OTHER_PROTO_NAME),
+		other => panic!("unexpected event: {:?}", other),
+	}
+	assert!(next_multi_event(&mut handler).is_none());
+
+	// Unknown protocols are ignored.
+	handler.inject_upgrade_error(2, ProtocolsHandlerUpgrErr::Timeout);
+	assert!(next_multi_event(&mut handler).is_none());
+}
+
+/// Accepts the substream opened by `open_recording_substream` with `answer`, and waits for
+/// it to be closed by the remote.
+fn accept_and_wait_closed(handler: &mut NotifsInHandler<MockSocket>, answer: NotifsInHandlerIn) {
+	expect_event!(handler, NotifsInHandlerOut::OpenRequest { .. });
+	handler.handle_event(answer);
+	expect_event!(handler, NotifsInHandlerOut::Closed { .. });
+}
+
+#[test]
+fn accept_default_sends_cached_handshake() {
+	let mut handler = NotifsInHandlerProto::new(
+		PROTO_NAME,
+		&b"hello"[..],
+		DEFAULT_MAX_HANDSHAKE_SIZE,
+		1024,
+		Duration::from_secs(20),
+	).build_handler();
+
+	let written = open_recording_substream(&mut handler);
+	accept_and_wait_closed(&mut handler, NotifsInHandlerIn::AcceptDefault { generation: 0 });
+	assert_eq!(&written.lock().unwrap()[..], b"\x05hello");
+
+	handler.handle_event(NotifsInHandlerIn::UpdateHandshake { handshake: b"world!".to_vec() });
+	let written = open_recording_substream(&mut handler);
+	accept_and_wait_closed(&mut handler, NotifsInHandlerIn::AcceptDefault { generation: 1 });
+	assert_eq!(&written.lock().unwrap()[..], b"\x06world!");
+}
+
+#[test]
+fn explicit_accept_updates_cached_handshake() {
+	let mut handler = NotifsInHandlerProto::new(
+		PROTO_NAME,
+		&b"hello"[..],
+		DEFAULT_MAX_HANDSHAKE_SIZE,
+		1024,
+		Duration::from_secs(20),
+	).build_handler();
+
+	let written = open_recording_substream(&mut handler);
+	let answer = NotifsInHandlerIn::Accept { generation: 0, handshake: b"foo".to_vec() };
+	accept_and_wait_closed(&mut handler, answer);
+	assert_eq!(&written.lock().unwrap()[..], b"\x03foo");
+
+	let written = open_recording_substream(&mut handler);
+	accept_and_wait_closed(&mut handler, NotifsInHandlerIn::AcceptDefault { generation: 1 });
+	assert_eq!(&written.lock().unwrap()[..], b"\x03foo");
+}
+
+#[test]
+fn accept_after_remote_closed() {
+	// The remote closes the substream before our `Accept` arrives.
+	let mut handler = build_handler();
+	open_closing_substream(&mut handler, &[]);
+	let _ = next_event(&mut handler);
+	expect_pending(&mut handler);
+	assert_eq!(handler.state(), NotifsInState::PendingAcceptRefuse);
+
+	handler.handle_event(NotifsInHandlerIn::Accept { generation: 0, handshake: Vec::new() });
+	expect_event!(&mut handler, NotifsInHandlerOut::Closed { generation: 0, reason } =>
+		assert_eq!(reason, NotifsInClosedReason::Remote));
+	assert_eq!(handler.state(), NotifsInState::Closed);
+	expect_pending(&mut handler);
+}
+
+#[test]
+fn refuse_drops_substream() {
+	let mut handler = build_handler();
+	open_substream(&mut handler, &[&b"foo"[..]]);
+	let _ = next_event(&mut handler);
+
+	handler.handle_event(NotifsInHandlerIn::Refuse { generation: 0 });
+	assert_eq!(handler.state(), NotifsInState::Closed);
+	assert_eq!(handler.stats().open_requests_refused, 1);
+	expect_pending(&mut handler);
+
+	// A late `Accept` for the refused substream is ignored.
+	handler.handle_event(NotifsInHandlerIn::Accept { generation: 0, handshake: Vec::new() });
+	assert_eq!(handler.state(), NotifsInState::Closed);
+	assert_eq!(handler.stats().open_requests_accepted, 0);
+	expect_pending(&mut handler);
+}
+
+#[test]
+fn frames_then_pending_then_eof() {
+	let mut handler = build_handler();
+	let counter = Arc::new(CountingWaker(AtomicUsize::new(0)));
+	let waker = task::waker(counter.clone());
+	let mut cx = Context::from_waker(&waker);
+
+	open_interrupted_substream(&mut handler, &[&b"foo"[..], &b"bar"[..]], 1, MockEnd::Eof);
+	let _ = handler.poll_event(&mut cx);
+	handler.handle_event(NotifsInHandlerIn::Accept { generation: 0, handshake: Vec::new() });
+
+	let mut received = Vec::new();
+	let closed = loop {
+		match handler.poll_event(&mut cx) {
+			Poll::Ready(ProtocolsHandlerEvent::Custom(NotifsInHandlerOut::Notif { message, .. })) =>
+				received.push(message),
+			Poll::Ready(ProtocolsHandlerEvent::Custom(NotifsInHandlerOut::Closed { generation, .. })) =>
+				break generation,
+			// The socket wakes up the task when it returns `Pending`.
+			Poll::Pending => assert!(counter.0.load(Ordering::SeqCst) > 0),
+			_ => panic!("unexpected event"),
+		}
+	};
+
+	assert_eq!(closed, 0);
+	assert_eq!(received, vec![&b"foo"[..], &b"bar"[..]]);
+}
+
+#[test]
+fn debug_output() {
+	let mut handler = build_handler();
+	open_substream(&mut handler, &[&b"secret"[..]]);
+	accept(&mut handler, 0);
+	let _ = next_event(&mut handler);
+
+	let output = format!("{:?}", handler);
+	assert!(output.contains("\"/test/proto/1\""));
+	assert!(output.contains("state: Open"));
+	assert!(output.contains("substream_present: true"));
+	assert!(output.contains("queued_events: 0"));
+	assert!(output.contains("notifications_received: 1"));
+	assert!(output.contains("bytes_received: 6"));
+	// Message contents are never printed.
+	assert!(!output.contains("secret"));
+}
+
+#[test]
+fn debug_output_truncates_protocol_name() {
+	let name = vec![b'a'; 16 * 1024];
+	let handler = NotifsInHandlerProto::new(
+		name,
+		Vec::new(),
+		DEFAULT_MAX_HANDSHAKE_SIZE,
+		1024,
+		Duration::from_secs(20),
+	).build_handler::<MockSocket>();
+
+	let output = format!("{:?}", handler);
+	assert!(output.contains(&format!("\"{}\"...", "a".repeat(MAX_DEBUG_PROTOCOL_NAME_LEN))));
+	assert!(output.len() < 1024);
+}
+
+fn build_ack_handler(window: usize) -> NotifsInHandler<MockSocket> {
+	build_proto().with_ack_window(window).build_handler()
+}
+
+#[test]
+fn ack_window_withholds_reads() {
+	let mut handler = build_ack_handler(2);
+	open_substream(&mut handler, &[&b"foo"[..], &b"bar"[..], &b"baz"[..]]);
+	accept(&mut handler, 0);
+
+	expect_notif(&mut handler, 0, b"foo");
+	expect_notif(&mut handler, 0, b"bar");
+	expect_pending(&mut handler);
+
+	handler.handle_event(NotifsInHandlerIn::NotifAck { generation: 0 });
+	expect_notif(&mut handler, 0, b"baz");
+	expect_pending(&mut handler);
+}
+
+#[test]
+fn ack_window_limits_batches() {
+	let mut handler = build_proto()
+		.with_ack_window(2)
+		.with_batching(NotifsInBatching { max_messages: 8, max_bytes: 1024 })
+		.build_handler();
+	open_substream(&mut handler, &[&b"foo"[..], &b"bar"[..], &b"baz"[..]]);
+	accept(&mut handler, 0);
+
+	expect_event!(&mut handler, NotifsInHandlerOut::NotifBatch { messages, .. } =>
+		assert_eq!(messages, vec![&b"foo"[..], &b"bar"[..]]));
+	expect_pending(&mut handler);
+}
+
+#[test]
+fn closed_with_outstanding_acks() {
+	let mut handler = build_ack_handler(1);
+	open_closing_substream(&mut handler, &[&b"foo"[..]]);
+	accept(&mut handler, 0);
+	expect_notif(&mut handler, 0, b"foo");
+
+	// The closing of the substream is noticed once the notification has been acknowledged.
+	expect_pending(&mut handler);
+	handler.handle_event(NotifsInHandlerIn::NotifAck { generation: 0 });
+	expect_event!(&mut handler, NotifsInHandlerOut::Closed { generation: 0, reason } =>
+		assert_eq!(reason, NotifsInClosedReason::Remote));
+
+	// Closing explicitly doesn't wait for the acknowledgements.
+	open_substream(&mut handler, &[&b"bar"[..], &b"baz"[..]]);
+	accept(&mut handler, 1);
+	expect_notif(&mut handler, 1, b"bar");
+	handler.handle_event(NotifsInHandlerIn::Close);
+	expect_event!(&mut handler, NotifsInHandlerOut::Closed { generation: 1, reason } =>
+		assert_eq!(reason, NotifsInClosedReason::Local));
+	handler.handle_event(NotifsInHandlerIn::NotifAck { generation: 1 });
+	expect_pending(&mut handler);
+}
+
+#[test]
+fn ack_for_replaced_substream_ignored() {
+	let mut handler = build_ack_handler(1);
+	open_closing_substream(&mut handler, &[&b"foo"[..]]);
+	accept(&mut handler, 0);
+	expect_notif(&mut handler, 0, b"foo");
+	handler.handle_event(NotifsInHandlerIn::Close);
+	let _ = next_event(&mut handler);
+
+	// The window of the new substream doesn't inherit the outstanding acknowledgement.
+	open_substream(&mut handler, &[&b"bar"[..], &b"baz"[..]]);
+	accept(&mut handler, 1);
+	expect_notif(&mut handler, 1, b"bar");
+
+	// A late acknowledgement concerning the previous substream doesn't open the window.
+	handler.handle_event(NotifsInHandlerIn::NotifAck { generation: 0 });
+	expect_pending(&mut handler);
+	handler.handle_event(NotifsInHandlerIn::NotifAck { generation: 1 });
+	expect_notif(&mut handler, 1, b"baz");
+}
+
+#[test]
+fn bandwidth_reported_periodically() {
+	let mut handler = build_proto()
+		.with_bandwidth_report_interval(Duration::from_millis(10))
+		.build_handler();
+	open_substream(&mut handler, &[&[0; 10][..], &[0; 32][..]]);
+	handler.handle_event(NotifsInHandlerIn::Accept { generation: 0, handshake: Vec::new() });
+
+	// The timer may fire between the two notifications.
+	let mut received = 0;
+	while received < 42 {
+		match wait_event(&mut handler) {
+			NotifsInHandlerOut::BytesReceivedReport { since_last } => received += since_last,
+			NotifsInHandlerOut::OpenRequest { .. } | NotifsInHandlerOut::Notif { .. } => {},
+			other => panic!("expected BytesReceivedReport, got {:?}", other),
+		}
+	}
+	assert_eq!(received, 42);
+
+	// Nothing has been received since the last report.
+	wait_wake(&mut handler);
+	expect_pending(&mut handler);
+}
+
+#[test]
+fn tiny_report_intervals_do_not_spin() {
+	let mut handler = build_proto()
+		.with_stats_interval(Duration::from_secs(0))
+		.with_bandwidth_report_interval(Duration::from_nanos(1))
+		.build_handler();
+	assert_eq!(handler.stats_timer.as_ref().map(|(_, i)| *i), Some(MIN_REPORT_INTERVAL));
+	assert_eq!(handler.bandwidth_timer.as_ref().map(|(_, i)| *i), Some(MIN_REPORT_INTERVAL));
+
+	open_substream(&mut handler, &[&b"foo"[..]]);
+	handler.handle_event(NotifsInHandlerIn::Accept { generation: 0, handshake: Vec::new() });
+
+	// Every call returns, with at most one report at a time.
+	let (mut stats, mut reports) = (0, 0);
+	while stats < 2 || reports < 1 {
+		match wait_event(&mut handler) {
+			NotifsInHandlerOut::Stats(_) => stats += 1,
+			NotifsInHandlerOut::BytesReceivedReport { since_last } => {
+				assert_eq!(since_last, 3);
+				reports += 1;
+			},
+			NotifsInHandlerOut::OpenRequest { .. } | NotifsInHandlerOut::Notif { .. } => {},
+			other => panic!("unexpected event: {:?}", other),
+		}
+	}
+}
+
+#[test]
+fn idle_timeout_closes_substream_with_pings() {
+	let mut handler = build_proto()
+		.with_idle_timeout(Duration::from_millis(50))
+		.build_handler();
+	// An empty notification, a ping, then a notification.
+	open_substream_with_name(&mut handler, b"/test/proto/1/ping", &[&[0][..], &[][..], &b"\0foo"[..]]);
+	expect_event!(&mut handler, NotifsInHandlerOut::OpenRequest { protocol_name, .. } =>
+		assert_eq!(&protocol_name[..], PROTO_NAME));
+	handler.handle_event(NotifsInHandlerIn::Accept { generation: 0, handshake: Vec::new() });
+
+	expect_notif(&mut handler, 0, b"");
+	expect_notif(&mut handler, 0, b"foo");
+
+	match wait_event(&mut handler) {
+		NotifsInHandlerOut::Closed { generation: 0, reason } =>
+			assert_eq!(reason, NotifsInClosedReason::IdleTimeout),
+		other => panic!("expected Closed, got {:?}", other),
+	}
+	assert_eq!(handler.state(), NotifsInState::Closed);
+}
+
+#[test]
+fn no_idle_timeout_without_pings() {
+	// The remote doesn't support pings, and is never considered idle, even with a timeout
+	// that is always expired.
+	let mut handler = build_proto()
+		.with_idle_timeout(Duration::from_secs(0))
+		.build_handler();
+	open_substream(&mut handler, &[&[][..], &b"foo"[..]]);
+	accept(&mut handler, 0);
+
+	expect_notif(&mut handler, 0, b"");
+	expect_notif(&mut handler, 0, b"foo");
+	expect_pending(&mut handler);
+	assert_eq!(handler.state(), NotifsInState::Open);
+}
+
+#[test]
+fn unknown_frame_tag_is_protocol_violation() {
+	let mut handler = build_proto()
+		.with_idle_timeout(Duration::from_secs(20))
+		.build_handler();
+	open_substream_with_name(&mut handler, b"/test/proto/1/ping", &[&b"\x01foo"[..]]);
+	accept(&mut handler, 0);
+
+	expect_event!(&mut handler, NotifsInHandlerOut::ProtocolViolation {
+		generation: 0,
+		error: NotificationsInError::UnknownFrameTag { tag },
+	} => assert_eq!(tag, 1));
+	assert_eq!(handler.state(), NotifsInState::Closed);
+}
+
+#[test]
+fn open_request_reports_version() {
+	let versions = vec![
+		NotificationsVersion {
+			name: Cow::Borrowed(&b"/test/proto/2"[..]),
+			version: 2,
+			framing: NotificationsFraming::Tagged,
+		},
+		NotificationsVersion {
+			name: Cow::Borrowed(PROTO_NAME),
+			version: 1,
+			framing: NotificationsFraming::Plain,
+		},
+	];
+
+	// Notifications are decoded according to the framing of the negotiated version.
+	for &(name, version, frame) in &[
+		(&b"/test/proto/2"[..], 2, &b"\0foo"[..]),
+		(PROTO_NAME, 1, &b"foo"[..]),
+	] {
+		let mut handler = build_proto().with_versions(versions.clone()).build_handler();
+		open_substream_with_name(&mut handler, name, &[frame]);
+		expect_event!(&mut handler, NotifsInHandlerOut::OpenRequest { protocol_name, version: v, .. } => {
+			assert_eq!(&protocol_name[..], name);
+			assert_eq!(v, Some(version));
+		});
+		handler.handle_event(NotifsInHandlerIn::Accept { generation: 0, handshake: Vec::new() });
+		expect_notif(&mut handler, 0, b"foo");
+	}
+
+	// Protocols registered without versions don't report any.
+	let mut handler = build_handler();
+	open_substream(&mut handler, &[]);
+	expect_event!(&mut handler, NotifsInHandlerOut::OpenRequest { version, .. } =>
+		assert_eq!(version, None));
+}
+
+#[test]
+fn shutdown_flushes_handshake() {
+	let mut handler = build_handler();
+	let written = open_recording_substream(&mut handler);
+	let _ = next_event(&mut handler);
+	handler.handle_event(NotifsInHandlerIn::Accept { generation: 0, handshake: b"foo".to_vec() });
+	handler.handle_event(NotifsInHandlerIn::Shutdown);
+	assert!(handler.keep_alive() == KeepAlive::Yes);
+
+	expect_event!(&mut handler, NotifsInHandlerOut::Closed { generation: 0, reason } =>
+		assert_eq!(reason, NotifsInClosedReason::Shutdown));
+	expect_event!(&mut handler, NotifsInHandlerOut::ShutdownComplete { timed_out } =>
+		assert!(!timed_out));
+	assert_eq!(&written.lock().unwrap()[..], b"\x03foo");
+	assert!(handler.keep_alive() == KeepAlive::No);
+
+	// Substreams opened afterwards are dropped.
+	open_substream(&mut handler, &[]);
+	expect_pending(&mut handler);
+	assert_eq!(handler.state(), NotifsInState::Closed);
+}
+
+#[test]
+fn shutdown_refuses_pending_substream() {
+	let mut handler = build_handler();
+	let written = open_recording_substream(&mut handler);
+	let _ = next_event(&mut handler);
+	handler.handle_event(NotifsInHandlerIn::Shutdown);
+
+	expect_event!(&mut handler, NotifsInHandlerOut::ShutdownComplete { timed_out } =>
+		assert!(!timed_out));
+	assert!(written.lock().unwrap().is_empty());
+	assert_eq!(handler.stats().open_requests_refused, 1);
+
+	// The late answer to the `OpenRequest` is ignored.
+	handler.handle_event(NotifsInHandlerIn::Accept { generation: 0, handshake: Vec::new() });
+	assert_eq!(handler.state(), NotifsInState::Closed);
+	expect_pending(&mut handler);
+}
+
+#[test]
+fn shutdown_bounded_by_timeout() {
+	let mut handler = build_proto()
+		.with_shutdown_timeout(Duration::from_millis(50))
+		.build_handler();
+	let mut socket = mock_socket(&[], MockEnd::Pending);
+	socket.stalled_writes = true;
+	let upgrade = handler.in_protocol.clone().upgrade_inbound(socket, Cow::Borrowed(PROTO_NAME));
+	let (msg, substream) = executor::block_on(upgrade).unwrap();
+	handler.inject_substream(msg, substream);
+	let _ = next_event(&mut handler);
+	handler.handle_event(NotifsInHandlerIn::Accept { generation: 0, handshake: b"foo".to_vec() });
+	handler.handle_event(NotifsInHandlerIn::Shutdown);
+
+	expect_event!(&mut handler, NotifsInHandlerOut::Closed { generation: 0, .. });
+	// The handshake can't be sent.
+	assert_eq!(handler.draining.len(), 1);
+	assert!(handler.keep_alive() == KeepAlive::Yes);
+
+	match wait_event(&mut handler) {
+		NotifsInHandlerOut::ShutdownComplete { timed_out } => assert!(timed_out),
+		other => panic!("expected ShutdownComplete, got {:?}", other),
+	}
+	assert!(handler.keep_alive() == KeepAlive::No);
+}
@@ -21,7 +21,10 @@
 //! >			protocols, you need to create multiple instances and group them.
 //!
 
-use crate::protocol::generic_proto::upgrade::{NotificationsOut, NotificationsOutSubstream, NotificationsHandshakeError};
+use crate::protocol::generic_proto::upgrade::{
+	CompressionConfig, NotificationsOut, NotificationsOutSubstream, NotificationsHandshakeError,
+	NotificationsVersion,
+};
 use futures::prelude::*;
 use libp2p::core::{ConnectedPoint, PeerId};
 use libp2p::core::upgrade::{DeniedUpgrade, InboundUpgrade, OutboundUpgrade};
@@ -56,6 +59,13 @@ const INITIAL_KEEPALIVE_TIME: Duration = Duration::from_secs(5);
 pub struct NotifsOutHandlerProto {
 	/// Name of the protocol to negotiate.
 	protocol_name: Cow<'static, [u8]>,
+	/// If `Some`, we first try to negotiate the compressed version of the protocol.
+	compression: Option<CompressionConfig>,
+	/// If `Some`, we first try to negotiate the version of the protocol with keep-alive pings.
+	keep_alive_pings: Option<Duration>,
+	/// Versions of the protocol to try by order of preference. If empty, only `protocol_name`
+	/// is tried.
+	versions: Vec<NotificationsVersion>,
 }
 
 impl NotifsOutHandlerProto {
@@ -64,8 +74,37 @@ impl NotifsOutHandlerProto {
 	pub fn new(protocol_name: impl Into<Cow<'static, [u8]>>) -> Self {
 		NotifsOutHandlerProto {
 			protocol_name: protocol_name.into(),
+			compression: None,
+			keep_alive_pings: None,
+			versions: Vec::new(),
 		}
 	}
+
+	/// Compresses the notifications we send if the remote supports it. Falls back to sending
+	/// uncompressed notifications otherwise.
+	pub fn with_compression(mut self, config: CompressionConfig) -> Self {
+		self.compression = Some(config);
+		self
+	}
+
+	/// Sends a keep-alive ping whenever nothing has been sent for `interval`, if the remote
+	/// supports it.
+	///
+	/// # Panics
+	///
+	/// Panics if `interval` is zero.
+	pub fn with_keep_alive_pings(mut self, interval: Duration) -> Self {
+		assert!(interval != Duration::from_secs(0), "Keep-alive pings interval must not be zero");
+		self.keep_alive_pings = Some(interval);
+		self
+	}
+
+	/// Tries to negotiate the given versions of the protocol, by order of preference, instead of
+	/// the protocol name passed to `new`.
+	pub fn with_versions(mut self, versions: impl IntoIterator<Item = NotificationsVersion>) -> Self {
+		self.versions = versions.into_iter().collect();
+		self
+	}
 }
 
 impl IntoProtocolsHandler for NotifsOutHandlerProto {
@@ -78,6 +117,9 @@ impl IntoProtocolsHandler for NotifsOutHandlerProto {
 	fn into_handler(self, _: &PeerId, _: &ConnectedPoint) -> Self::Handler {
 		NotifsOutHandler {
 			protocol_name: self.protocol_name,
+			compression: self.compression,
+			keep_alive_pings: self.keep_alive_pings,
+			versions: self.versions,
 			when_connection_open: Instant::now(),
 			state: State::Disabled,
 			events_queue: SmallVec::new(),
@@ -97,6 +139,16 @@ pub struct NotifsOutHandler {
 	/// Name of the protocol to negotiate.
 	protocol_name: Cow<'static, [u8]>,
 
+	/// If `Some`, we first try to negotiate the compressed version of the protocol.
+	compression: Option<CompressionConfig>,
+
+	/// If `Some`, we first try to negotiate the version of the protocol with keep-alive pings.
+	keep_alive_pings: Option<Duration>,
+
+	/// Versions of the protocol to try by order of preference. If empty, only `protocol_name`
+	/// is tried.
+	versions: Vec<NotificationsVersion>,
+
 	/// Relationship with the node we're connected to.
 	state: State,
 
@@ -205,6 +257,24 @@ impl NotifsOutHandler {
 	pub fn protocol_name(&self) -> &[u8] {
 		&self.protocol_name
 	}
+
+	/// Builds the upgrade to use in order to open a substream.
+	fn out_protocol(&self, initial_message: Vec<u8>) -> NotificationsOut {
+		let proto = NotificationsOut::new(self.protocol_name.clone(), initial_message);
+		let proto = if self.versions.is_empty() {
+			proto
+		} else {
+			proto.with_versions(self.versions.iter().cloned())
+		};
+		let proto = match self.compression {
+			Some(config) => proto.with_compression(config),
+			None => proto,
+		};
+		match self.keep_alive_pings {
+			Some(interval) => proto.with_keep_alive_pings(interval),
+			None => proto,
+		}
+	}
 }
 
 impl ProtocolsHandler for NotifsOutHandler {
@@ -254,7 +324,7 @@ impl ProtocolsHandler for NotifsOutHandler {
 			NotifsOutHandlerIn::Enable { initial_message } => {
 				match mem::replace(&mut self.state, State::Poisoned) {
 					State::Disabled => {
-						let proto = NotificationsOut::new(self.protocol_name.clone(), initial_message.clone());
+						let proto = self.out_protocol(initial_message.clone());
 						self.events_queue.push(ProtocolsHandlerEvent::OutboundSubstreamRequest {
 							protocol: SubstreamProtocol::new(proto).with_timeout(OPEN_TIMEOUT),
 							info: (),
@@ -274,7 +344,7 @@ impl ProtocolsHandler for NotifsOutHandler {
 							);
 						}
 
-						let proto = NotificationsOut::new(self.protocol_name.clone(), initial_message.clone());
+						let proto = self.out_protocol(initial_message.clone());
 						self.events_queue.push(ProtocolsHandlerEvent::OutboundSubstreamRequest {
 							protocol: SubstreamProtocol::new(proto).with_timeout(OPEN_TIMEOUT),
 							info: (),
@@ -362,7 +432,7 @@ impl ProtocolsHandler for NotifsOutHandler {
 						// We try to re-open a substream.
 						let initial_message = mem::replace(initial_message, Vec::new());
 						self.state = State::Opening { initial_message: initial_message.clone() };
-						let proto = NotificationsOut::new(self.protocol_name.clone(), initial_message);
+						let proto = self.out_protocol(initial_message);
 						self.events_queue.push(ProtocolsHandlerEvent::OutboundSubstreamRequest {
 							protocol: SubstreamProtocol::new(proto).with_timeout(OPEN_TIMEOUT),
 							info: (),
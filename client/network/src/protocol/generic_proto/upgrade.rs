@@ -22,12 +22,17 @@ pub use self::legacy::{
 	RegisteredProtocolSubstream
 };
 pub use self::notifications::{
+	CompressionConfig,
+	DEFAULT_MAX_HANDSHAKE_SIZE,
 	NotificationsIn,
 	NotificationsInSubstream,
 	NotificationsOut,
 	NotificationsOutSubstream,
 	NotificationsHandshakeError,
+	NotificationsInError,
 	NotificationsOutError,
+	NotificationsFraming,
+	NotificationsVersion,
 };
 
 mod collec;
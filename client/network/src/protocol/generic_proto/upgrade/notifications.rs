@@ -25,6 +25,11 @@
 ///   Afterwards, the sending side of B is closed.
 /// - If instead B refuses the connection (which typically happens because no empty slot is
 ///   available), then it immediately closes the substream without sending back anything.
+///   Alternatively, B can send back a refusal frame before closing the substream, explaining
+///   why it refused. The refusal frame is a reason of at most `MAX_REFUSAL_REASON_SIZE` bytes,
+///   prefixed with a variable-length integer equal to its length plus
+///   `REFUSAL_LENGTH_OFFSET`. As this is above the maximum handshake size, a node that doesn't
+///   know about refusal frames treats it as an invalid handshake.
 /// - Node A can then send notifications to B, prefixed with a variable-length integer indicating
 ///   the length of the message.
 /// - Node A closes its writing side if it doesn't want the notifications substream anymore.
@@ -32,17 +37,53 @@
 /// Notification substreams are unidirectional. If A opens a substream with B, then B is
 /// encouraged but not required to open a substream to A as well.
 ///
+/// If compression is enabled, the protocol name suffixed with `COMPRESSION_SUFFIX` is advertised
+/// in addition to the regular one. When this name is negotiated, each notification is
+/// individually compressed with zstd. Peers that don't support compression negotiate the regular
+/// name instead. The handshake messages are never compressed.
+///
+/// Similarly, the protocol name suffixed with `PINGS_SUFFIX` (after `COMPRESSION_SUFFIX` if both
+/// are used) indicates that node A sends keep-alive pings. On such a substream, a zero-length
+/// frame is a ping, and every notification is prefixed with a `NOTIFICATION_TAG` byte, so that an
+/// empty notification is a one-byte frame. Frames starting with any other byte are reserved and
+/// considered as a protocol violation. The tag is added after the compression, if any.
+///
+/// A protocol can also be registered as an ordered list of versions, each with its own name and
+/// framing. The names are negotiated by order of preference, and the notifications are framed
+/// according to the version that has been negotiated. `NotificationsFraming::Tagged` is the same
+/// framing as the one used when keep-alive pings are negotiated.
+///
 
 use bytes::BytesMut;
 use futures::{prelude::*, ready};
 use futures_codec::Framed;
+use futures_timer::Delay;
 use libp2p::core::{UpgradeInfo, InboundUpgrade, OutboundUpgrade, upgrade};
 use log::error;
-use std::{borrow::Cow, collections::VecDeque, io, iter, mem, pin::Pin, task::{Context, Poll}};
+use std::{borrow::Cow, collections::VecDeque, convert::TryFrom as _, error, io, iter, mem, vec};
+use std::{pin::Pin, task::{Context, Poll}, time::Duration};
+use wasm_timer::Instant;
 use unsigned_varint::codec::UviBytes;
 
 /// Maximum allowed size of the two handshake messages, in bytes.
 const MAX_HANDSHAKE_SIZE: usize = 1024;
+/// Default value for the maximum size of the handshake sent by the remote on an inbound
+/// substream. See [`NotificationsIn::new`].
+pub const DEFAULT_MAX_HANDSHAKE_SIZE: usize = MAX_HANDSHAKE_SIZE;
+/// Maximum allowed size of the reason sent in a refusal frame, in bytes.
+pub const MAX_REFUSAL_REASON_SIZE: usize = 256;
+/// Added to the length of the reason of a refusal frame in order to distinguish it from a
+/// handshake. Relies on the handshake sent back on an accepted substream never being above
+/// `MAX_HANDSHAKE_SIZE`, whatever the `max_handshake_size` passed to `NotificationsIn::new`.
+const REFUSAL_LENGTH_OFFSET: usize = MAX_HANDSHAKE_SIZE + 1;
+/// Suffix appended to the name of a protocol in order to indicate that notifications are
+/// compressed with zstd.
+pub const COMPRESSION_SUFFIX: &[u8] = b"/zstd";
+/// Suffix appended to the name of a protocol in order to indicate that keep-alive pings are sent
+/// on the substream.
+pub const PINGS_SUFFIX: &[u8] = b"/ping";
+/// Byte that precedes every notification on a substream with keep-alive pings.
+const NOTIFICATION_TAG: u8 = 0;
 /// Maximum number of buffered messages before we consider the remote unresponsive and kill the
 /// substream.
 const MAX_PENDING_MESSAGES: usize = 256;
@@ -53,6 +94,50 @@ const MAX_PENDING_MESSAGES: usize = 256;
 pub struct NotificationsIn {
 	/// Protocol name to use when negotiating the substream.
 	protocol_name: Cow<'static, [u8]>,
+	/// Other protocol names that we accept as well, by order of preference.
+	fallback_names: Vec<Cow<'static, [u8]>>,
+	/// Maximum allowed size, in bytes, of the handshake sent by the remote.
+	max_handshake_size: usize,
+	/// Maximum allowed size, in bytes, of a single notification.
+	max_notification_size: u64,
+	/// If `Some`, we also accept substreams whose notifications are compressed.
+	compression: Option<CompressionConfig>,
+	/// If true, we also accept substreams on which the remote sends keep-alive pings.
+	keep_alive_pings: bool,
+	/// Versions of the protocol, if it has been registered with `with_versions`. The names of
+	/// the versions are also in `protocol_name` and `fallback_names`.
+	versions: Vec<NotificationsVersion>,
+}
+
+/// Version of a notifications protocol. See [`NotificationsIn::with_versions`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NotificationsVersion {
+	/// Protocol name to negotiate for this version.
+	pub name: Cow<'static, [u8]>,
+	/// Version number, reported once the substream has been negotiated.
+	pub version: u32,
+	/// How notifications are framed on the substream.
+	pub framing: NotificationsFraming,
+}
+
+/// How notifications are framed on a substream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotificationsFraming {
+	/// Every frame is a notification. This is the framing of the protocols registered without
+	/// versions.
+	Plain,
+	/// Every notification is prefixed with a tag byte, and empty frames are keep-alive pings.
+	Tagged,
+}
+
+/// Configuration of the compression of notifications.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressionConfig {
+	/// Maximum size, in bytes, of a received notification once decompressed. Notifications that
+	/// decompress to more than this are considered as a protocol violation.
+	pub max_decompressed_size: usize,
+	/// zstd compression level to use for the notifications we send. `0` means the zstd default.
+	pub level: i32,
 }
 
 /// Upgrade that opens a substream, waits for the remote to accept by sending back a status
@@ -63,6 +148,24 @@ pub struct NotificationsOut {
 	protocol_name: Cow<'static, [u8]>,
 	/// Message to send when we start the handshake.
 	initial_message: Vec<u8>,
+	/// If `Some`, we first try to negotiate the compressed version of the protocol.
+	compression: Option<CompressionConfig>,
+	/// If `Some`, we first try to negotiate the version of the protocol with keep-alive pings,
+	/// and send a ping whenever nothing has been sent for this long.
+	keep_alive_pings: Option<Duration>,
+	/// Versions of the protocol to try, by order of preference, if it has been registered with
+	/// `with_versions`. Otherwise, only `protocol_name` is tried.
+	versions: Vec<NotificationsVersion>,
+}
+
+/// Optional features of the notifications protocol, negotiated through suffixes of the
+/// protocol name.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Features {
+	/// Notifications are compressed. See `COMPRESSION_SUFFIX`.
+	compression: bool,
+	/// Keep-alive pings are sent. See `PINGS_SUFFIX`.
+	pings: bool,
 }
 
 /// A substream for incoming notification messages.
@@ -74,6 +177,22 @@ pub struct NotificationsInSubstream<TSubstream> {
 	#[pin]
 	socket: Framed<TSubstream, UviBytes<io::Cursor<Vec<u8>>>>,
 	handshake: NotificationsInSubstreamHandshake,
+	/// Name of the protocol that has been negotiated.
+	protocol_name: Cow<'static, [u8]>,
+	/// Maximum allowed size, in bytes, of a single notification. Enforced by the codec of
+	/// `socket`, and only kept here in order to be reported in errors.
+	max_notification_size: u64,
+	/// If `Some`, notifications are compressed and must not decompress to more than this number
+	/// of bytes.
+	max_decompressed_size: Option<usize>,
+	/// If true, the remote sends keep-alive pings. Implies `tagged_frames`.
+	keep_alive_pings: bool,
+	/// If true, notifications are prefixed with a tag and empty frames are pings.
+	tagged_frames: bool,
+	/// Version that has been negotiated, if the protocol has been registered with versions.
+	version: Option<u32>,
+	/// When we last received a frame, ping or notification, or when the substream was opened.
+	last_frame_received: Instant,
 }
 
 /// State of the handshake sending back process.
@@ -82,6 +201,9 @@ enum NotificationsInSubstreamHandshake {
 	NotSent,
 	/// User gave us the handshake message. Trying to push it in the socket.
 	PendingSend(Vec<u8>),
+	/// User refused the substream. Trying to write the remaining bytes of the refusal frame
+	/// directly in the socket.
+	PendingRefusal(Vec<u8>),
 	/// Handshake message was pushed in the socket. Still need to flush.
 	Close,
 	/// Handshake message successfully sent.
@@ -96,30 +218,126 @@ pub struct NotificationsOutSubstream<TSubstream> {
 	socket: Framed<TSubstream, UviBytes<io::Cursor<Vec<u8>>>>,
 	/// Queue of messages waiting to be sent.
 	messages_queue: VecDeque<Vec<u8>>,
+	/// If true, a ping is waiting to be sent. Pings aren't part of `messages_queue`, so that at
+	/// most one of them is pending at any time, but count against `MAX_PENDING_MESSAGES`.
+	ping_pending: bool,
 	/// If true, we need to flush `socket`.
 	need_flush: bool,
+	/// If `Some`, notifications are compressed with this zstd level before being sent.
+	compression_level: Option<i32>,
+	/// If `Some`, we send a ping when the timer fires, and reset it to the duration whenever
+	/// something is sent. Only ever `Some` if `tagged_frames` is true.
+	ping_timer: Option<(Delay, Duration)>,
+	/// If true, notifications are prefixed with a tag.
+	tagged_frames: bool,
+	/// Version that has been negotiated, if the protocol has been registered with versions.
+	version: Option<u32>,
 }
 
 impl NotificationsIn {
 	/// Builds a new potential upgrade.
-	pub fn new(protocol_name: impl Into<Cow<'static, [u8]>>) -> Self {
+	///
+	/// If the remote announces a handshake above `max_handshake_size` bytes, the upgrade is
+	/// aborted with a [`NotificationsHandshakeError::TooLarge`] before anything is allocated.
+	/// Notifications whose size is above `max_notification_size` bytes are refused before being
+	/// buffered, and the substream then produces a [`NotificationsInError::TooLarge`].
+	///
+	/// `max_handshake_size` only applies to the handshake sent by the remote. The handshake sent
+	/// back with [`NotificationsInSubstream::send_handshake`] is always limited to
+	/// `DEFAULT_MAX_HANDSHAKE_SIZE` bytes, as larger ones can't be told apart from refusals.
+	pub fn new(
+		protocol_name: impl Into<Cow<'static, [u8]>>,
+		max_handshake_size: usize,
+		max_notification_size: u64,
+	) -> Self {
 		NotificationsIn {
 			protocol_name: protocol_name.into(),
+			fallback_names: Vec::new(),
+			max_handshake_size,
+			max_notification_size,
+			compression: None,
+			keep_alive_pings: false,
+			versions: Vec::new(),
+		}
+	}
+
+	/// Accepts the given versions of the protocol, by order of preference, instead of the names
+	/// passed to `new` and `with_fallback_names`. The version that has been negotiated is
+	/// reported by [`NotificationsInSubstream::version`].
+	///
+	/// Has no effect if `versions` is empty.
+	pub fn with_versions(mut self, versions: impl IntoIterator<Item = NotificationsVersion>) -> Self {
+		let versions = versions.into_iter().collect::<Vec<_>>();
+		let mut names = versions.iter().map(|v| v.name.clone());
+		match names.next() {
+			Some(main) => {
+				self.protocol_name = main;
+				self.fallback_names = names.collect();
+			},
+			None => {
+				error!(target: "sub-libp2p", "Tried to register a protocol without any version");
+				return self;
+			},
 		}
+		self.versions = versions;
+		self
+	}
+
+	/// Also accepts substreams whose notifications are compressed. The compressed versions of
+	/// the protocol names are advertised in addition to the regular ones.
+	pub fn with_compression(mut self, config: CompressionConfig) -> Self {
+		self.compression = Some(config);
+		self
+	}
+
+	/// Also accepts substreams on which the remote sends keep-alive pings. The versions of the
+	/// protocol names with pings are advertised in addition to the regular ones. Pings are
+	/// swallowed by the substream, see [`NotificationsInSubstream::last_frame_received`].
+	pub fn with_keep_alive_pings(mut self) -> Self {
+		self.keep_alive_pings = true;
+		self
+	}
+
+	/// Also accepts the given protocol names, in addition to the main one. Typically used in
+	/// order to keep accepting the previous name of a protocol that has been renamed.
+	pub fn with_fallback_names(
+		mut self,
+		fallback_names: impl IntoIterator<Item = impl Into<Cow<'static, [u8]>>>
+	) -> Self {
+		self.fallback_names = fallback_names.into_iter().map(Into::into).collect();
+		self
 	}
 
 	/// Returns the name of the protocol that we accept.
-	pub fn protocol_name(&self) -> &[u8] {
+	pub fn protocol_name(&self) -> &Cow<'static, [u8]> {
 		&self.protocol_name
 	}
+
+	/// Returns the names of all the protocols that we accept, starting with the main one.
+	pub fn protocol_names(&self) -> impl Iterator<Item = &[u8]> {
+		iter::once(&self.protocol_name).chain(self.fallback_names.iter()).map(|n| &n[..])
+	}
+
+	/// Returns the optional features that we support.
+	fn features(&self) -> Features {
+		Features {
+			compression: self.compression.is_some(),
+			pings: self.keep_alive_pings,
+		}
+	}
 }
 
 impl UpgradeInfo for NotificationsIn {
 	type Info = Cow<'static, [u8]>;
-	type InfoIter = iter::Once<Self::Info>;
+	type InfoIter = vec::IntoIter<Self::Info>;
 
 	fn protocol_info(&self) -> Self::InfoIter {
-		iter::once(self.protocol_name.clone())
+		self.features()
+			.subsets()
+			.into_iter()
+			.flat_map(|features| self.protocol_names().map(move |name| features.protocol_name(name)))
+			.collect::<Vec<_>>()
+			.into_iter()
 	}
 }
 
@@ -133,25 +351,55 @@ where TSubstream: AsyncRead + AsyncWrite + Unpin + Send + 'static,
 	fn upgrade_inbound(
 		self,
 		mut socket: TSubstream,
-		_: Self::Info,
+		protocol_name: Self::Info,
 	) -> Self::Future {
 		Box::pin(async move {
 			let initial_message_len = unsigned_varint::aio::read_usize(&mut socket).await?;
-			if initial_message_len > MAX_HANDSHAKE_SIZE {
+			if initial_message_len > self.max_handshake_size {
 				return Err(NotificationsHandshakeError::TooLarge {
 					requested: initial_message_len,
-					max: MAX_HANDSHAKE_SIZE,
+					max: self.max_handshake_size,
 				});
 			}
 
 			let mut initial_message = vec![0u8; initial_message_len];
 			if !initial_message.is_empty() {
-				socket.read(&mut initial_message).await?;
+				socket.read_exact(&mut initial_message).await?;
 			}
 
+			let mut codec = UviBytes::default();
+			codec.set_max_len(usize::try_from(self.max_notification_size).unwrap_or(usize::max_value()));
+
+			// If a version of a protocol with some features has been negotiated, we report the
+			// regular name so that the features are transparent for the user.
+			let supported = self.features();
+			let negotiated = iter::once(&self.protocol_name)
+				.chain(self.fallback_names.iter())
+				.filter_map(|base| {
+					Features::parse(&protocol_name, base)
+						.filter(|features| features.is_subset_of(supported))
+						.map(|features| (base.clone(), features))
+				})
+				.next();
+			let (protocol_name, features) = negotiated
+				.unwrap_or_else(|| (protocol_name, Features::default()));
+			let version = find_version(&self.versions, &protocol_name);
+			let tagged_frames = features.pings ||
+				version.map_or(false, |v| v.framing == NotificationsFraming::Tagged);
+			let version = version.map(|v| v.version);
+
 			let substream = NotificationsInSubstream {
-				socket: Framed::new(socket, UviBytes::default()),
+				socket: Framed::new(socket, codec),
 				handshake: NotificationsInSubstreamHandshake::NotSent,
+				protocol_name,
+				max_notification_size: self.max_notification_size,
+				max_decompressed_size: self.compression
+					.filter(|_| features.compression)
+					.map(|config| config.max_decompressed_size),
+				keep_alive_pings: features.pings,
+				tagged_frames,
+				version,
+				last_frame_received: Instant::now(),
 			};
 
 			Ok((initial_message, substream))
@@ -162,7 +410,34 @@ where TSubstream: AsyncRead + AsyncWrite + Unpin + Send + 'static,
 impl<TSubstream> NotificationsInSubstream<TSubstream>
 where TSubstream: AsyncRead + AsyncWrite,
 {
+	/// Returns true if the notifications received on this substream are compressed. The
+	/// decompression is done transparently.
+	pub fn is_compressed(&self) -> bool {
+		self.max_decompressed_size.is_some()
+	}
+
+	/// Returns true if the remote sends keep-alive pings on this substream. The pings are
+	/// swallowed and never returned as notifications.
+	pub fn has_keep_alive_pings(&self) -> bool {
+		self.keep_alive_pings
+	}
+
+	/// Returns the version of the protocol that has been negotiated, or `None` if the protocol
+	/// has been registered without versions.
+	pub fn version(&self) -> Option<u32> {
+		self.version
+	}
+
+	/// Returns when we last received a frame, ping or notification, on this substream. If nothing
+	/// has been received yet, returns when the substream was opened.
+	pub fn last_frame_received(&self) -> Instant {
+		self.last_frame_received
+	}
+
 	/// Sends the handshake in order to inform the remote that we accept the substream.
+	///
+	/// The handshake must not be above `DEFAULT_MAX_HANDSHAKE_SIZE` bytes, otherwise the remote
+	/// rejects it or mistakes it for a refusal.
 	pub fn send_handshake(&mut self, message: impl Into<Vec<u8>>) {
 		match self.handshake {
 			NotificationsInSubstreamHandshake::NotSent => {}
@@ -172,45 +447,186 @@ where TSubstream: AsyncRead + AsyncWrite,
 			}
 		}
 
-		self.handshake = NotificationsInSubstreamHandshake::PendingSend(message.into());
+		let message = message.into();
+		if message.len() > MAX_HANDSHAKE_SIZE {
+			error!(target: "sub-libp2p", "Networking handshake sent back is above allowed protocol limit");
+		}
+
+		self.handshake = NotificationsInSubstreamHandshake::PendingSend(message);
+	}
+
+	/// Informs the remote that we refuse the substream, with the given reason. The reason is
+	/// truncated to `MAX_REFUSAL_REASON_SIZE` bytes.
+	///
+	/// The refusal frame is sent when the substream is closed with `poll_close`. No message will
+	/// come afterwards.
+	pub fn send_refusal(&mut self, reason: impl Into<Vec<u8>>) {
+		match self.handshake {
+			NotificationsInSubstreamHandshake::NotSent => {}
+			_ => {
+				error!(target: "sub-libp2p", "Tried to refuse a substream after sending handshake");
+				return;
+			}
+		}
+
+		let mut reason = reason.into();
+		reason.truncate(MAX_REFUSAL_REASON_SIZE);
+
+		let mut len_buf = unsigned_varint::encode::usize_buffer();
+		let len = unsigned_varint::encode::usize(REFUSAL_LENGTH_OFFSET + reason.len(), &mut len_buf);
+		let mut frame = Vec::with_capacity(len.len() + reason.len());
+		frame.extend_from_slice(len);
+		frame.extend_from_slice(&reason);
+		self.handshake = NotificationsInSubstreamHandshake::PendingRefusal(frame);
+	}
+
+	/// Returns the name of the protocol that has been negotiated for this substream.
+	pub fn protocol_name(&self) -> &Cow<'static, [u8]> {
+		&self.protocol_name
 	}
 }
 
-impl<TSubstream> Stream for NotificationsInSubstream<TSubstream>
+impl<TSubstream> NotificationsInSubstream<TSubstream>
 where TSubstream: AsyncRead + AsyncWrite + Unpin,
 {
-	type Item = Result<BytesMut, io::Error>;
-
-	fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+	/// Finishes sending the handshake passed to `send_handshake`, without reading anything from
+	/// the substream. Returns `Ready` once the handshake has been sent, or immediately if no
+	/// handshake is being sent.
+	pub fn poll_send_handshake(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), io::Error>> {
 		let mut this = self.project();
 
-		// This `Stream` implementation first tries to send back the handshake if necessary.
 		loop {
 			match mem::replace(this.handshake, NotificationsInSubstreamHandshake::Sent) {
-				NotificationsInSubstreamHandshake::Sent =>
-					return Stream::poll_next(this.socket.as_mut(), cx),
-				NotificationsInSubstreamHandshake::NotSent =>
-					return Poll::Pending,
 				NotificationsInSubstreamHandshake::PendingSend(msg) =>
 					match Sink::poll_ready(this.socket.as_mut(), cx) {
 						Poll::Ready(_) => {
 							*this.handshake = NotificationsInSubstreamHandshake::Close;
-							match Sink::start_send(this.socket.as_mut(), io::Cursor::new(msg)) {
-								Ok(()) => {},
-								Err(err) => return Poll::Ready(Some(Err(err))),
-							}
+							Sink::start_send(this.socket.as_mut(), io::Cursor::new(msg))?;
+						},
+						Poll::Pending => {
+							*this.handshake = NotificationsInSubstreamHandshake::PendingSend(msg);
+							return Poll::Pending;
 						},
-						Poll::Pending =>
-							*this.handshake = NotificationsInSubstreamHandshake::PendingSend(msg),
 					},
 				NotificationsInSubstreamHandshake::Close =>
 					match Sink::poll_close(this.socket.as_mut(), cx)? {
-						Poll::Ready(()) =>
-							*this.handshake = NotificationsInSubstreamHandshake::Sent,
-						Poll::Pending =>
-							*this.handshake = NotificationsInSubstreamHandshake::Close,
+						Poll::Ready(()) => return Poll::Ready(Ok(())),
+						Poll::Pending => {
+							*this.handshake = NotificationsInSubstreamHandshake::Close;
+							return Poll::Pending;
+						},
+					},
+				st => {
+					*this.handshake = st;
+					return Poll::Ready(Ok(()));
+				},
+			}
+		}
+	}
+
+	/// Closes the substream, after having finished sending the handshake if `send_handshake`
+	/// has been called, or the refusal frame if `send_refusal` has been called.
+	///
+	/// If neither has been called, the substream is closed without sending anything, which the
+	/// remote interprets as a refusal.
+	pub fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), io::Error>> {
+		let mut this = self.project();
+
+		loop {
+			match mem::replace(this.handshake, NotificationsInSubstreamHandshake::Sent) {
+				NotificationsInSubstreamHandshake::PendingSend(msg) =>
+					match Sink::poll_ready(this.socket.as_mut(), cx) {
+						Poll::Ready(_) => {
+							*this.handshake = NotificationsInSubstreamHandshake::Close;
+							Sink::start_send(this.socket.as_mut(), io::Cursor::new(msg))?;
+						},
+						Poll::Pending => {
+							*this.handshake = NotificationsInSubstreamHandshake::PendingSend(msg);
+							return Poll::Pending;
+						},
 					},
+				NotificationsInSubstreamHandshake::PendingRefusal(mut frame) => {
+					// Nothing has ever been written through the codec, so we can write the frame
+					// directly in the underlying socket.
+					let socket: &mut TSubstream = &mut **this.socket.as_mut().get_mut();
+					match AsyncWrite::poll_write(Pin::new(socket), cx, &frame) {
+						Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+						Poll::Ready(Ok(n)) => {
+							frame.drain(..n);
+							*this.handshake = if frame.is_empty() {
+								NotificationsInSubstreamHandshake::Close
+							} else {
+								NotificationsInSubstreamHandshake::PendingRefusal(frame)
+							};
+						},
+						Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+						Poll::Pending => {
+							*this.handshake = NotificationsInSubstreamHandshake::PendingRefusal(frame);
+							return Poll::Pending;
+						},
+					}
+				},
+				NotificationsInSubstreamHandshake::NotSent |
+				NotificationsInSubstreamHandshake::Close |
+				NotificationsInSubstreamHandshake::Sent =>
+					return Sink::poll_close(this.socket.as_mut(), cx),
+			}
+		}
+	}
+}
+
+impl<TSubstream> Stream for NotificationsInSubstream<TSubstream>
+where TSubstream: AsyncRead + AsyncWrite + Unpin,
+{
+	type Item = Result<BytesMut, NotificationsInError>;
+
+	fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+		// This `Stream` implementation first tries to send back the handshake if necessary.
+		if let Err(err) = ready!(self.as_mut().poll_send_handshake(cx)) {
+			return Poll::Ready(Some(Err(From::from(err))));
+		}
+
+		let mut this = self.project();
+		match this.handshake {
+			NotificationsInSubstreamHandshake::Sent => {},
+			// The substream hasn't been accepted yet, or has been refused and is waiting to be
+			// closed.
+			NotificationsInSubstreamHandshake::NotSent |
+			NotificationsInSubstreamHandshake::PendingRefusal(_) => return Poll::Pending,
+			NotificationsInSubstreamHandshake::PendingSend(_) |
+			NotificationsInSubstreamHandshake::Close =>
+				unreachable!("poll_send_handshake only returns Ready once the handshake is sent; qed"),
+		}
+
+		loop {
+			let mut frame = match ready!(Stream::poll_next(this.socket.as_mut(), cx)) {
+				Some(Ok(frame)) => frame,
+				// `UviBytes` reports frames above its maximum length as `PermissionDenied` after
+				// having read the length prefix but before buffering the frame.
+				Some(Err(ref err)) if err.kind() == io::ErrorKind::PermissionDenied =>
+					return Poll::Ready(Some(Err(NotificationsInError::TooLarge {
+						max: *this.max_notification_size,
+					}))),
+				Some(Err(err)) => return Poll::Ready(Some(Err(From::from(err)))),
+				None => return Poll::Ready(None),
+			};
+
+			*this.last_frame_received = Instant::now();
+			if *this.tagged_frames {
+				if frame.is_empty() {
+					// Ping. Read the next frame.
+					continue;
+				}
+				let tag = frame.split_to(1)[0];
+				if tag != NOTIFICATION_TAG {
+					return Poll::Ready(Some(Err(NotificationsInError::UnknownFrameTag { tag })));
+				}
 			}
+
+			return Poll::Ready(Some(match *this.max_decompressed_size {
+				Some(max) => decompress(&frame, max),
+				None => Ok(frame),
+			}));
 		}
 	}
 }
@@ -226,16 +642,76 @@ impl NotificationsOut {
 		NotificationsOut {
 			protocol_name: protocol_name.into(),
 			initial_message,
+			compression: None,
+			keep_alive_pings: None,
+			versions: Vec::new(),
+		}
+	}
+
+	/// Tries to negotiate the given versions of the protocol, by order of preference, instead of
+	/// the name passed to `new`. The version that has been negotiated is reported by
+	/// [`NotificationsOutSubstream::version`].
+	///
+	/// Has no effect if `versions` is empty.
+	pub fn with_versions(mut self, versions: impl IntoIterator<Item = NotificationsVersion>) -> Self {
+		let versions = versions.into_iter().collect::<Vec<_>>();
+		match versions.first() {
+			Some(main) => self.protocol_name = main.name.clone(),
+			None => {
+				error!(target: "sub-libp2p", "Tried to register a protocol without any version");
+				return self;
+			},
+		}
+		self.versions = versions;
+		self
+	}
+
+	/// Returns the names of the protocols to try, by order of preference.
+	fn protocol_names(&self) -> Vec<&[u8]> {
+		if self.versions.is_empty() {
+			vec![&self.protocol_name[..]]
+		} else {
+			self.versions.iter().map(|v| &v.name[..]).collect()
 		}
 	}
+
+	/// First tries to negotiate the compressed version of the protocol, and falls back to the
+	/// regular one if the remote doesn't support it.
+	pub fn with_compression(mut self, config: CompressionConfig) -> Self {
+		self.compression = Some(config);
+		self
+	}
+
+	/// First tries to negotiate the version of the protocol with keep-alive pings, and falls
+	/// back to the regular one if the remote doesn't support it. If negotiated, a ping is sent
+	/// whenever nothing has been sent for `interval`, as long as the substream is flushed.
+	///
+	/// # Panics
+	///
+	/// Panics if `interval` is zero.
+	pub fn with_keep_alive_pings(mut self, interval: Duration) -> Self {
+		assert!(interval != Duration::from_secs(0), "Keep-alive pings interval must not be zero");
+		self.keep_alive_pings = Some(interval);
+		self
+	}
 }
 
 impl UpgradeInfo for NotificationsOut {
 	type Info = Cow<'static, [u8]>;
-	type InfoIter = iter::Once<Self::Info>;
+	type InfoIter = vec::IntoIter<Self::Info>;
 
 	fn protocol_info(&self) -> Self::InfoIter {
-		iter::once(self.protocol_name.clone())
+		let features = Features {
+			compression: self.compression.is_some(),
+			pings: self.keep_alive_pings.is_some(),
+		};
+
+		let names = self.protocol_names();
+		features.subsets()
+			.into_iter()
+			.flat_map(|features| names.iter().map(move |name| features.protocol_name(name)))
+			.collect::<Vec<_>>()
+			.into_iter()
 	}
 }
 
@@ -249,13 +725,38 @@ where TSubstream: AsyncRead + AsyncWrite + Unpin + Send + 'static,
 	fn upgrade_outbound(
 		self,
 		mut socket: TSubstream,
-		_: Self::Info,
+		protocol_name: Self::Info,
 	) -> Self::Future {
 		Box::pin(async move {
+			let (base, features) = self.protocol_names()
+				.into_iter()
+				.filter_map(|base| Features::parse(&protocol_name, base).map(|f| (base, f)))
+				.next()
+				.unwrap_or((&self.protocol_name[..], Features::default()));
+			let version = find_version(&self.versions, base);
+			let tagged_frames = features.pings ||
+				version.map_or(false, |v| v.framing == NotificationsFraming::Tagged);
+			let version = version.map(|v| v.version);
+			let compression_level = self.compression
+				.filter(|_| features.compression)
+				.map(|config| config.level);
+			let ping_timer = self.keep_alive_pings
+				.filter(|_| features.pings)
+				.map(|interval| (Delay::new(interval), interval));
+
 			upgrade::write_with_len_prefix(&mut socket, &self.initial_message).await?;
 
 			// Reading handshake.
 			let handshake_len = unsigned_varint::aio::read_usize(&mut socket).await?;
+			if handshake_len >= REFUSAL_LENGTH_OFFSET &&
+				handshake_len - REFUSAL_LENGTH_OFFSET <= MAX_REFUSAL_REASON_SIZE
+			{
+				let mut reason = vec![0u8; handshake_len - REFUSAL_LENGTH_OFFSET];
+				if !reason.is_empty() {
+					socket.read_exact(&mut reason).await?;
+				}
+				return Err(NotificationsHandshakeError::Refused { reason });
+			}
 			if handshake_len > MAX_HANDSHAKE_SIZE {
 				return Err(NotificationsHandshakeError::TooLarge {
 					requested: handshake_len,
@@ -265,18 +766,42 @@ where TSubstream: AsyncRead + AsyncWrite + Unpin + Send + 'static,
 
 			let mut handshake = vec![0u8; handshake_len];
 			if !handshake.is_empty() {
-				socket.read(&mut handshake).await?;
+				socket.read_exact(&mut handshake).await?;
 			}
 
 			Ok((handshake, NotificationsOutSubstream {
 				socket: Framed::new(socket, UviBytes::default()),
 				messages_queue: VecDeque::with_capacity(MAX_PENDING_MESSAGES),
+				ping_pending: false,
 				need_flush: false,
+				compression_level,
+				ping_timer,
+				tagged_frames,
+				version,
 			}))
 		})
 	}
 }
 
+impl<TSubstream> NotificationsOutSubstream<TSubstream> {
+	/// Returns true if the notifications sent on this substream are compressed. The compression
+	/// is done transparently.
+	pub fn is_compressed(&self) -> bool {
+		self.compression_level.is_some()
+	}
+
+	/// Returns true if keep-alive pings are sent on this substream.
+	pub fn has_keep_alive_pings(&self) -> bool {
+		self.ping_timer.is_some()
+	}
+
+	/// Returns the version of the protocol that has been negotiated, or `None` if the protocol
+	/// has been registered without versions.
+	pub fn version(&self) -> Option<u32> {
+		self.version
+	}
+}
+
 impl<TSubstream> Sink<Vec<u8>> for NotificationsOutSubstream<TSubstream>
 	where TSubstream: AsyncRead + AsyncWrite + Unpin,
 {
@@ -287,10 +812,29 @@ impl<TSubstream> Sink<Vec<u8>> for NotificationsOutSubstream<TSubstream>
 	}
 
 	fn start_send(mut self: Pin<&mut Self>, item: Vec<u8>) -> Result<(), Self::Error> {
-		if self.messages_queue.len() >= MAX_PENDING_MESSAGES {
+		if self.messages_queue.len() + usize::from(self.ping_pending) >= MAX_PENDING_MESSAGES {
 			return Err(NotificationsOutError::Clogged);
 		}
 
+		let item = match self.compression_level {
+			Some(level) => zstd::stream::encode_all(&item[..], level)?,
+			None => item,
+		};
+
+		// Sending the notification keeps the substream alive as well.
+		if let Some((timer, interval)) = &mut self.ping_timer {
+			timer.reset(*interval);
+		}
+
+		let item = if self.tagged_frames {
+			let mut tagged = Vec::with_capacity(item.len() + 1);
+			tagged.push(NOTIFICATION_TAG);
+			tagged.extend_from_slice(&item);
+			tagged
+		} else {
+			item
+		};
+
 		self.messages_queue.push_back(item);
 		Ok(())
 	}
@@ -298,12 +842,27 @@ impl<TSubstream> Sink<Vec<u8>> for NotificationsOutSubstream<TSubstream>
 	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
 		let mut this = self.project();
 
-		while !this.messages_queue.is_empty() {
+		if let Some((timer, interval)) = this.ping_timer {
+			// Schedule a ping if nothing has been sent for a whole interval. The timer is polled
+			// only once, and we wake up the task after resetting it in order for the next call
+			// to poll it again and register the waker for the next ping.
+			if let Poll::Ready(()) = Pin::new(&mut *timer).poll(cx) {
+				timer.reset(*interval);
+				cx.waker().wake_by_ref();
+				*this.ping_pending = true;
+			}
+		}
+
+		while *this.ping_pending || !this.messages_queue.is_empty() {
 			match Sink::poll_ready(this.socket.as_mut(), cx) {
 				Poll::Ready(Err(err)) => return Poll::Ready(Err(From::from(err))),
 				Poll::Ready(Ok(())) => {
-					let msg = this.messages_queue.pop_front()
-						.expect("checked for !is_empty above; qed");
+					let msg = if mem::replace(this.ping_pending, false) {
+						Vec::new()
+					} else {
+						this.messages_queue.pop_front()
+							.expect("checked for !is_empty above; qed")
+					};
 					Sink::start_send(this.socket.as_mut(), io::Cursor::new(msg))?;
 					*this.need_flush = true;
 				},
@@ -333,6 +892,89 @@ impl<TSubstream> Sink<Vec<u8>> for NotificationsOutSubstream<TSubstream>
 	}
 }
 
+impl Features {
+	/// Returns all the combinations of features that are enabled in `self`, by order of
+	/// preference. The most featureful combination comes first and no feature at all comes last.
+	fn subsets(self) -> Vec<Features> {
+		let mut subsets = Vec::with_capacity(4);
+		for &compression in &[true, false] {
+			for &pings in &[true, false] {
+				let features = Features { compression, pings };
+				if features.is_subset_of(self) {
+					subsets.push(features);
+				}
+			}
+		}
+		subsets
+	}
+
+	/// Returns true if every feature enabled in `self` is enabled in `other` as well.
+	fn is_subset_of(self, other: Features) -> bool {
+		(!self.compression || other.compression) && (!self.pings || other.pings)
+	}
+
+	/// Returns the name of the version of the protocol `base` with these features.
+	fn protocol_name(self, base: &[u8]) -> Cow<'static, [u8]> {
+		let mut name = base.to_vec();
+		if self.compression {
+			name.extend_from_slice(COMPRESSION_SUFFIX);
+		}
+		if self.pings {
+			name.extend_from_slice(PINGS_SUFFIX);
+		}
+		Cow::Owned(name)
+	}
+
+	/// If `negotiated` is the name of a version of the protocol `base`, returns its features.
+	fn parse(negotiated: &[u8], base: &[u8]) -> Option<Features> {
+		if !negotiated.starts_with(base) {
+			return None;
+		}
+
+		let mut suffix = &negotiated[base.len()..];
+		let mut features = Features::default();
+		if suffix.starts_with(COMPRESSION_SUFFIX) {
+			features.compression = true;
+			suffix = &suffix[COMPRESSION_SUFFIX.len()..];
+		}
+		if suffix.starts_with(PINGS_SUFFIX) {
+			features.pings = true;
+			suffix = &suffix[PINGS_SUFFIX.len()..];
+		}
+
+		if suffix.is_empty() {
+			Some(features)
+		} else {
+			None
+		}
+	}
+}
+
+/// Returns the version whose name is `name`, if any.
+fn find_version<'a>(versions: &'a [NotificationsVersion], name: &[u8]) -> Option<&'a NotificationsVersion> {
+	versions.iter().find(|v| &v.name[..] == name)
+}
+
+/// Decompresses a received notification. Refuses to produce more than `max` bytes, in order to
+/// protect against decompression bombs.
+fn decompress(frame: &[u8], max: usize) -> Result<BytesMut, NotificationsInError> {
+	use std::io::Read as _;
+
+	let decoder = zstd::stream::read::Decoder::new(frame)
+		.map_err(|_| NotificationsInError::Decompression { max })?;
+
+	// We read one byte more than allowed in order to detect notifications that are too large.
+	let mut decompressed = Vec::new();
+	decoder.take(u64::try_from(max).unwrap_or(u64::max_value()).saturating_add(1))
+		.read_to_end(&mut decompressed)
+		.map_err(|_| NotificationsInError::Decompression { max })?;
+	if decompressed.len() > max {
+		return Err(NotificationsInError::Decompression { max });
+	}
+
+	Ok(BytesMut::from(&decompressed[..]))
+}
+
 /// Error generated by sending on a notifications out substream.
 #[derive(Debug, derive_more::From, derive_more::Display)]
 pub enum NotificationsHandshakeError {
@@ -350,6 +992,14 @@ pub enum NotificationsHandshakeError {
 
 	/// Error while decoding the variable-length integer.
 	VarintDecode(unsigned_varint::decode::Error),
+
+	/// The remote has refused the substream and explained why.
+	#[display(fmt = "Substream refused by remote: {}", String::from_utf8_lossy(reason))]
+	#[from(ignore)]
+	Refused {
+		/// Reason sent by the remote.
+		reason: Vec<u8>,
+	},
 }
 
 impl From<unsigned_varint::io::ReadError> for NotificationsHandshakeError {
@@ -365,6 +1015,43 @@ impl From<unsigned_varint::io::ReadError> for NotificationsHandshakeError {
 	}
 }
 
+/// Error generated by receiving on a notifications in substream.
+#[derive(Debug, derive_more::From, derive_more::Display)]
+pub enum NotificationsInError {
+	/// I/O error on the substream.
+	Io(io::Error),
+
+	/// Remote has sent a notification above the maximum allowed size. This is a protocol
+	/// violation.
+	#[display(fmt = "Notification above the maximum allowed size of {} bytes", max)]
+	#[from(ignore)]
+	TooLarge {
+		/// Maximum allowed.
+		max: u64,
+	},
+
+	/// Remote has sent a compressed notification that is either invalid or decompresses to more
+	/// than the maximum allowed size. This is a protocol violation.
+	#[display(fmt = "Invalid compressed notification or above {} bytes once decompressed", max)]
+	#[from(ignore)]
+	Decompression {
+		/// Maximum allowed size once decompressed.
+		max: usize,
+	},
+
+	/// Remote has sent a frame starting with a reserved byte on a substream with keep-alive
+	/// pings. This is a protocol violation.
+	#[display(fmt = "Frame with unknown tag {}", tag)]
+	#[from(ignore)]
+	UnknownFrameTag {
+		/// First byte of the frame.
+		tag: u8,
+	},
+}
+
+impl error::Error for NotificationsInError {
+}
+
 /// Error generated by sending on a notifications out substream.
 #[derive(Debug, derive_more::From, derive_more::Display)]
 pub enum NotificationsOutError {
@@ -381,12 +1068,41 @@ pub enum NotificationsOutError {
 
 #[cfg(test)]
 mod tests {
-	use super::{NotificationsIn, NotificationsOut};
+	use super::{
+		CompressionConfig, DEFAULT_MAX_HANDSHAKE_SIZE, MAX_HANDSHAKE_SIZE, MAX_PENDING_MESSAGES,
+		NotificationsHandshakeError, NotificationsIn, NotificationsInError, NotificationsOut,
+		NotificationsOutError, NotificationsOutSubstream, NotificationsFraming, NotificationsVersion,
+	};
 
 	use async_std::net::{TcpListener, TcpStream};
 	use futures::{prelude::*, channel::oneshot};
+	use futures_codec::Framed;
+	use futures_timer::Delay;
 	use libp2p::core::upgrade;
-	use std::pin::Pin;
+	use std::{borrow::Cow, collections::VecDeque, io, pin::Pin, task::{Context, Poll}, time::Duration};
+	use unsigned_varint::codec::UviBytes;
+
+	const COMPRESSION: CompressionConfig = CompressionConfig {
+		max_decompressed_size: 16 * 1024,
+		level: 0,
+	};
+
+	/// Versions 2 and 1 of the test protocol, by order of preference. Only version 2 uses the
+	/// tagged framing.
+	fn versions() -> Vec<NotificationsVersion> {
+		vec![
+			NotificationsVersion {
+				name: Cow::Borrowed(&b"/test/proto/2"[..]),
+				version: 2,
+				framing: NotificationsFraming::Tagged,
+			},
+			NotificationsVersion {
+				name: Cow::Borrowed(&b"/test/proto/1"[..]),
+				version: 1,
+				framing: NotificationsFraming::Plain,
+			},
+		]
+	}
 
 	#[test]
 	fn basic_works() {
@@ -412,7 +1128,7 @@ mod tests {
 			let (socket, _) = listener.accept().await.unwrap();
 			let (initial_message, mut substream) = upgrade::apply_inbound(
 				socket,
-				NotificationsIn::new(PROTO_NAME)
+				NotificationsIn::new(PROTO_NAME, DEFAULT_MAX_HANDSHAKE_SIZE, 1024 * 1024)
 			).await.unwrap();
 
 			assert_eq!(initial_message, b"initial message");
@@ -451,7 +1167,7 @@ mod tests {
 			let (socket, _) = listener.accept().await.unwrap();
 			let (initial_message, mut substream) = upgrade::apply_inbound(
 				socket,
-				NotificationsIn::new(PROTO_NAME)
+				NotificationsIn::new(PROTO_NAME, DEFAULT_MAX_HANDSHAKE_SIZE, 1024 * 1024)
 			).await.unwrap();
 
 			assert!(initial_message.is_empty());
@@ -490,7 +1206,7 @@ mod tests {
 			let (socket, _) = listener.accept().await.unwrap();
 			let (initial_msg, substream) = upgrade::apply_inbound(
 				socket,
-				NotificationsIn::new(PROTO_NAME)
+				NotificationsIn::new(PROTO_NAME, DEFAULT_MAX_HANDSHAKE_SIZE, 1024 * 1024)
 			).await.unwrap();
 
 			assert_eq!(initial_msg, b"hello");
@@ -503,19 +1219,23 @@ mod tests {
 	}
 
 	#[test]
-	fn large_initial_message_refused() {
+	fn refused_with_reason() {
 		const PROTO_NAME: &'static [u8] = b"/test/proto/1";
 		let (listener_addr_tx, listener_addr_rx) = oneshot::channel();
 
 		let client = async_std::task::spawn(async move {
 			let socket = TcpStream::connect(listener_addr_rx.await.unwrap()).await.unwrap();
-			let ret = upgrade::apply_outbound(
+			let outcome = upgrade::apply_outbound(
 				socket,
-				// We check that an initial message that is too large gets refused.
-				NotificationsOut::new(PROTO_NAME, (0..32768).map(|_| 0).collect::<Vec<_>>()),
+				NotificationsOut::new(PROTO_NAME, &b"hello"[..]),
 				upgrade::Version::V1
 			).await;
-			assert!(ret.is_err());
+
+			match outcome {
+				Err(upgrade::UpgradeError::Apply(NotificationsHandshakeError::Refused { reason })) =>
+					assert_eq!(reason, b"peer slots full"),
+				_ => panic!("expected a refusal"),
+			}
 		});
 
 		async_std::task::block_on(async move {
@@ -523,81 +1243,67 @@ mod tests {
 			listener_addr_tx.send(listener.local_addr().unwrap()).unwrap();
 
 			let (socket, _) = listener.accept().await.unwrap();
-			let ret = upgrade::apply_inbound(
+			let (_, mut substream) = upgrade::apply_inbound(
 				socket,
-				NotificationsIn::new(PROTO_NAME)
-			).await;
-			assert!(ret.is_err());
+				NotificationsIn::new(PROTO_NAME, DEFAULT_MAX_HANDSHAKE_SIZE, 1024 * 1024)
+			).await.unwrap();
+
+			substream.send_refusal(&b"peer slots full"[..]);
+			future::poll_fn(|cx| Pin::new(&mut substream).poll_close(cx)).await.unwrap();
 		});
 
 		async_std::task::block_on(client);
 	}
 
 	#[test]
-	fn large_handshake_refused() {
+	fn handshake_of_max_size_is_not_a_refusal() {
 		const PROTO_NAME: &'static [u8] = b"/test/proto/1";
 		let (listener_addr_tx, listener_addr_rx) = oneshot::channel();
 
 		let client = async_std::task::spawn(async move {
 			let socket = TcpStream::connect(listener_addr_rx.await.unwrap()).await.unwrap();
-			let ret = upgrade::apply_outbound(
+			let (handshake, _) = upgrade::apply_outbound(
 				socket,
-				NotificationsOut::new(PROTO_NAME, &b"initial message"[..]),
+				NotificationsOut::new(PROTO_NAME, &b"hello"[..]),
 				upgrade::Version::V1
-			).await;
-			assert!(ret.is_err());
+			).await.unwrap();
+
+			assert_eq!(handshake, vec![0xab; MAX_HANDSHAKE_SIZE]);
 		});
 
 		async_std::task::block_on(async move {
 			let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
 			listener_addr_tx.send(listener.local_addr().unwrap()).unwrap();
 
+			// The limit of the handshake sent back doesn't depend on the one of the inbound
+			// handshake.
 			let (socket, _) = listener.accept().await.unwrap();
-			let (initial_message, mut substream) = upgrade::apply_inbound(
+			let (_, mut substream) = upgrade::apply_inbound(
 				socket,
-				NotificationsIn::new(PROTO_NAME)
+				NotificationsIn::new(PROTO_NAME, 16, 1024 * 1024)
 			).await.unwrap();
-			assert_eq!(initial_message, b"initial message");
 
-			// We check that a handshake that is too large gets refused.
-			substream.send_handshake((0..32768).map(|_| 0).collect::<Vec<_>>());
-			let _ = substream.next().await;
+			substream.send_handshake(vec![0xab; MAX_HANDSHAKE_SIZE]);
+			future::poll_fn(|cx| Pin::new(&mut substream).poll_close(cx)).await.unwrap();
 		});
 
 		async_std::task::block_on(client);
 	}
 
 	#[test]
-	fn buffer_is_full_closes_connection() {
+	fn large_initial_message_refused() {
 		const PROTO_NAME: &'static [u8] = b"/test/proto/1";
 		let (listener_addr_tx, listener_addr_rx) = oneshot::channel();
 
 		let client = async_std::task::spawn(async move {
 			let socket = TcpStream::connect(listener_addr_rx.await.unwrap()).await.unwrap();
-			let (handshake, mut substream) = upgrade::apply_outbound(
+			let ret = upgrade::apply_outbound(
 				socket,
-				NotificationsOut::new(PROTO_NAME, vec![]),
+				// We check that an initial message that is too large gets refused.
+				NotificationsOut::new(PROTO_NAME, (0..32768).map(|_| 0).collect::<Vec<_>>()),
 				upgrade::Version::V1
-			).await.unwrap();
-
-			assert!(handshake.is_empty());
-
-			// Push an item and flush so that the test works.
-			substream.send(b"hello world".to_vec()).await.unwrap();
-
-			for _ in 0..32768 {
-				// Push an item on the sink without flushing until an error happens because the
-				// buffer is full.
-				let message = b"hello world!".to_vec();
-				if future::poll_fn(|cx| Sink::poll_ready(Pin::new(&mut substream), cx)).await.is_err() {
-					return Ok(());
-				}
-				if Sink::start_send(Pin::new(&mut substream), message).is_err() {
-					return Ok(());
-				}
-			}
-
-			Err(())
+			).await;
+			assert!(ret.is_err());
 		});
 
 		async_std::task::block_on(async move {
@@ -605,18 +1311,826 @@ mod tests {
 			listener_addr_tx.send(listener.local_addr().unwrap()).unwrap();
 
 			let (socket, _) = listener.accept().await.unwrap();
-			let (initial_message, mut substream) = upgrade::apply_inbound(
+			let ret = upgrade::apply_inbound(
 				socket,
-				NotificationsIn::new(PROTO_NAME)
-			).await.unwrap();
+				NotificationsIn::new(PROTO_NAME, DEFAULT_MAX_HANDSHAKE_SIZE, 1024 * 1024)
+			).await;
+			assert!(ret.is_err());
+		});
 
-			assert!(initial_message.is_empty());
-			substream.send_handshake(vec![]);
+		async_std::task::block_on(client);
+	}
 
-			// Process one message so that the handshake and all works.
-			let _ = substream.next().await.unwrap().unwrap();
+	#[test]
+	fn initial_message_at_custom_limit_accepted() {
+		const PROTO_NAME: &'static [u8] = b"/test/proto/1";
+		let (listener_addr_tx, listener_addr_rx) = oneshot::channel();
 
-			client.await.unwrap();
-		});
+		let client = async_std::task::spawn(async move {
+			let socket = TcpStream::connect(listener_addr_rx.await.unwrap()).await.unwrap();
+			let (_, mut substream) = upgrade::apply_outbound(
+				socket,
+				NotificationsOut::new(PROTO_NAME, vec![0; 16]),
+				upgrade::Version::V1
+			).await.unwrap();
+
+			substream.send(b"test message".to_vec()).await.unwrap();
+		});
+
+		async_std::task::block_on(async move {
+			let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+			listener_addr_tx.send(listener.local_addr().unwrap()).unwrap();
+
+			let (socket, _) = listener.accept().await.unwrap();
+			let (initial_message, mut substream) = upgrade::apply_inbound(
+				socket,
+				NotificationsIn::new(PROTO_NAME, 16, 1024 * 1024)
+			).await.unwrap();
+
+			assert_eq!(initial_message.len(), 16);
+			substream.send_handshake(vec![]);
+
+			let msg = substream.next().await.unwrap().unwrap();
+			assert_eq!(msg.as_ref(), b"test message");
+		});
+
+		async_std::task::block_on(client);
+	}
+
+	#[test]
+	fn initial_message_above_custom_limit_refused() {
+		const PROTO_NAME: &'static [u8] = b"/test/proto/1";
+		let (listener_addr_tx, listener_addr_rx) = oneshot::channel();
+
+		let client = async_std::task::spawn(async move {
+			let socket = TcpStream::connect(listener_addr_rx.await.unwrap()).await.unwrap();
+			let ret = upgrade::apply_outbound(
+				socket,
+				NotificationsOut::new(PROTO_NAME, vec![0; 17]),
+				upgrade::Version::V1
+			).await;
+			assert!(ret.is_err());
+		});
+
+		async_std::task::block_on(async move {
+			let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+			listener_addr_tx.send(listener.local_addr().unwrap()).unwrap();
+
+			let (socket, _) = listener.accept().await.unwrap();
+			let ret = upgrade::apply_inbound(
+				socket,
+				NotificationsIn::new(PROTO_NAME, 16, 1024 * 1024)
+			).await;
+
+			match ret {
+				Err(upgrade::UpgradeError::Apply(NotificationsHandshakeError::TooLarge {
+					requested,
+					max,
+				})) => {
+					assert_eq!(requested, 17);
+					assert_eq!(max, 16);
+				},
+				_ => panic!("expected the initial message to be refused"),
+			}
+		});
+
+		async_std::task::block_on(client);
+	}
+
+	#[test]
+	fn large_handshake_refused() {
+		const PROTO_NAME: &'static [u8] = b"/test/proto/1";
+		let (listener_addr_tx, listener_addr_rx) = oneshot::channel();
+
+		let client = async_std::task::spawn(async move {
+			let socket = TcpStream::connect(listener_addr_rx.await.unwrap()).await.unwrap();
+			let ret = upgrade::apply_outbound(
+				socket,
+				NotificationsOut::new(PROTO_NAME, &b"initial message"[..]),
+				upgrade::Version::V1
+			).await;
+			assert!(ret.is_err());
+		});
+
+		async_std::task::block_on(async move {
+			let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+			listener_addr_tx.send(listener.local_addr().unwrap()).unwrap();
+
+			let (socket, _) = listener.accept().await.unwrap();
+			let (initial_message, mut substream) = upgrade::apply_inbound(
+				socket,
+				NotificationsIn::new(PROTO_NAME, DEFAULT_MAX_HANDSHAKE_SIZE, 1024 * 1024)
+			).await.unwrap();
+			assert_eq!(initial_message, b"initial message");
+
+			// We check that a handshake that is too large gets refused.
+			substream.send_handshake((0..32768).map(|_| 0).collect::<Vec<_>>());
+			let _ = substream.next().await;
+		});
+
+		async_std::task::block_on(client);
+	}
+
+	#[test]
+	fn buffer_is_full_closes_connection() {
+		const PROTO_NAME: &'static [u8] = b"/test/proto/1";
+		let (listener_addr_tx, listener_addr_rx) = oneshot::channel();
+
+		let client = async_std::task::spawn(async move {
+			let socket = TcpStream::connect(listener_addr_rx.await.unwrap()).await.unwrap();
+			let (handshake, mut substream) = upgrade::apply_outbound(
+				socket,
+				NotificationsOut::new(PROTO_NAME, vec![]),
+				upgrade::Version::V1
+			).await.unwrap();
+
+			assert!(handshake.is_empty());
+
+			// Push an item and flush so that the test works.
+			substream.send(b"hello world".to_vec()).await.unwrap();
+
+			for _ in 0..32768 {
+				// Push an item on the sink without flushing until an error happens because the
+				// buffer is full.
+				let message = b"hello world!".to_vec();
+				if future::poll_fn(|cx| Sink::poll_ready(Pin::new(&mut substream), cx)).await.is_err() {
+					return Ok(());
+				}
+				if Sink::start_send(Pin::new(&mut substream), message).is_err() {
+					return Ok(());
+				}
+			}
+
+			Err(())
+		});
+
+		async_std::task::block_on(async move {
+			let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+			listener_addr_tx.send(listener.local_addr().unwrap()).unwrap();
+
+			let (socket, _) = listener.accept().await.unwrap();
+			let (initial_message, mut substream) = upgrade::apply_inbound(
+				socket,
+				NotificationsIn::new(PROTO_NAME, DEFAULT_MAX_HANDSHAKE_SIZE, 1024 * 1024)
+			).await.unwrap();
+
+			assert!(initial_message.is_empty());
+			substream.send_handshake(vec![]);
+
+			// Process one message so that the handshake and all works.
+			let _ = substream.next().await.unwrap().unwrap();
+
+			client.await.unwrap();
+		});
+	}
+
+	#[test]
+	fn notification_of_max_size_accepted() {
+		const PROTO_NAME: &'static [u8] = b"/test/proto/1";
+		let (listener_addr_tx, listener_addr_rx) = oneshot::channel();
+
+		let client = async_std::task::spawn(async move {
+			let socket = TcpStream::connect(listener_addr_rx.await.unwrap()).await.unwrap();
+			let (_, mut substream) = upgrade::apply_outbound(
+				socket,
+				NotificationsOut::new(PROTO_NAME, vec![]),
+				upgrade::Version::V1
+			).await.unwrap();
+
+			substream.send(vec![0; 1024]).await.unwrap();
+		});
+
+		async_std::task::block_on(async move {
+			let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+			listener_addr_tx.send(listener.local_addr().unwrap()).unwrap();
+
+			let (socket, _) = listener.accept().await.unwrap();
+			let (_, mut substream) = upgrade::apply_inbound(
+				socket,
+				NotificationsIn::new(PROTO_NAME, DEFAULT_MAX_HANDSHAKE_SIZE, 1024)
+			).await.unwrap();
+
+			substream.send_handshake(vec![]);
+
+			let msg = substream.next().await.unwrap().unwrap();
+			assert_eq!(msg.len(), 1024);
+		});
+
+		async_std::task::block_on(client);
+	}
+
+	#[test]
+	fn notification_above_max_size_refused() {
+		const PROTO_NAME: &'static [u8] = b"/test/proto/1";
+		let (listener_addr_tx, listener_addr_rx) = oneshot::channel();
+
+		let client = async_std::task::spawn(async move {
+			let socket = TcpStream::connect(listener_addr_rx.await.unwrap()).await.unwrap();
+			let (_, mut substream) = upgrade::apply_outbound(
+				socket,
+				NotificationsOut::new(PROTO_NAME, vec![]),
+				upgrade::Version::V1
+			).await.unwrap();
+
+			// The remote might close the substream before everything has been sent.
+			let _ = substream.send(vec![0; 1025]).await;
+		});
+
+		async_std::task::block_on(async move {
+			let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+			listener_addr_tx.send(listener.local_addr().unwrap()).unwrap();
+
+			let (socket, _) = listener.accept().await.unwrap();
+			let (_, mut substream) = upgrade::apply_inbound(
+				socket,
+				NotificationsIn::new(PROTO_NAME, DEFAULT_MAX_HANDSHAKE_SIZE, 1024)
+			).await.unwrap();
+
+			substream.send_handshake(vec![]);
+
+			match substream.next().await {
+				Some(Err(NotificationsInError::TooLarge { max })) => assert_eq!(max, 1024),
+				other => panic!("unexpected outcome: {:?}", other),
+			}
+		});
+
+		async_std::task::block_on(client);
+	}
+
+	#[test]
+	fn compressed_notifications() {
+		// The notification is above `max_notification_size` before compression only.
+		const PROTO_NAME: &'static [u8] = b"/test/proto/1";
+		let (listener_addr_tx, listener_addr_rx) = oneshot::channel();
+
+		let client = async_std::task::spawn(async move {
+			let socket = TcpStream::connect(listener_addr_rx.await.unwrap()).await.unwrap();
+			let (_, mut substream) = upgrade::apply_outbound(
+				socket,
+				NotificationsOut::new(PROTO_NAME, vec![])
+					.with_compression(COMPRESSION),
+				upgrade::Version::V1
+			).await.unwrap();
+
+			assert!(substream.is_compressed());
+			substream.send(vec![5; 4096]).await.unwrap();
+		});
+
+		async_std::task::block_on(async move {
+			let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+			listener_addr_tx.send(listener.local_addr().unwrap()).unwrap();
+
+			let (socket, _) = listener.accept().await.unwrap();
+			let (_, mut substream) = upgrade::apply_inbound(
+				socket,
+				NotificationsIn::new(PROTO_NAME, DEFAULT_MAX_HANDSHAKE_SIZE, 1024)
+					.with_compression(COMPRESSION)
+			).await.unwrap();
+
+			assert_eq!(&substream.protocol_name()[..], PROTO_NAME);
+			assert!(substream.is_compressed());
+			substream.send_handshake(vec![]);
+
+			let msg = substream.next().await.unwrap().unwrap();
+			assert_eq!(msg.as_ref(), &[5; 4096][..]);
+		});
+
+		async_std::task::block_on(client);
+	}
+
+	#[test]
+	fn compressed_out_uncompressed_in_falls_back() {
+		const PROTO_NAME: &'static [u8] = b"/test/proto/1";
+		let (listener_addr_tx, listener_addr_rx) = oneshot::channel();
+
+		let client = async_std::task::spawn(async move {
+			let socket = TcpStream::connect(listener_addr_rx.await.unwrap()).await.unwrap();
+			let (_, mut substream) = upgrade::apply_outbound(
+				socket,
+				NotificationsOut::new(PROTO_NAME, vec![])
+					.with_compression(COMPRESSION),
+				upgrade::Version::V1
+			).await.unwrap();
+
+			assert!(!substream.is_compressed());
+			substream.send(vec![5; 4096]).await.unwrap();
+		});
+
+		async_std::task::block_on(async move {
+			let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+			listener_addr_tx.send(listener.local_addr().unwrap()).unwrap();
+
+			let (socket, _) = listener.accept().await.unwrap();
+			let (_, mut substream) = upgrade::apply_inbound(
+				socket,
+				NotificationsIn::new(PROTO_NAME, DEFAULT_MAX_HANDSHAKE_SIZE, 1024 * 1024)
+			).await.unwrap();
+
+			assert_eq!(&substream.protocol_name()[..], PROTO_NAME);
+			assert!(!substream.is_compressed());
+			substream.send_handshake(vec![]);
+
+			let msg = substream.next().await.unwrap().unwrap();
+			assert_eq!(msg.as_ref(), &[5; 4096][..]);
+		});
+
+		async_std::task::block_on(client);
+	}
+
+	#[test]
+	fn uncompressed_out_compressed_in_falls_back() {
+		const PROTO_NAME: &'static [u8] = b"/test/proto/1";
+		let (listener_addr_tx, listener_addr_rx) = oneshot::channel();
+
+		let client = async_std::task::spawn(async move {
+			let socket = TcpStream::connect(listener_addr_rx.await.unwrap()).await.unwrap();
+			let (_, mut substream) = upgrade::apply_outbound(
+				socket,
+				NotificationsOut::new(PROTO_NAME, vec![]),
+				upgrade::Version::V1
+			).await.unwrap();
+
+			assert!(!substream.is_compressed());
+			substream.send(vec![5; 4096]).await.unwrap();
+		});
+
+		async_std::task::block_on(async move {
+			let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+			listener_addr_tx.send(listener.local_addr().unwrap()).unwrap();
+
+			let (socket, _) = listener.accept().await.unwrap();
+			let (_, mut substream) = upgrade::apply_inbound(
+				socket,
+				NotificationsIn::new(PROTO_NAME, DEFAULT_MAX_HANDSHAKE_SIZE, 1024 * 1024)
+					.with_compression(COMPRESSION)
+			).await.unwrap();
+
+			assert_eq!(&substream.protocol_name()[..], PROTO_NAME);
+			assert!(!substream.is_compressed());
+			substream.send_handshake(vec![]);
+
+			let msg = substream.next().await.unwrap().unwrap();
+			assert_eq!(msg.as_ref(), &[5; 4096][..]);
+		});
+
+		async_std::task::block_on(client);
+	}
+
+	#[test]
+	fn decompression_bomb_refused() {
+		const PROTO_NAME: &'static [u8] = b"/test/proto/1";
+		let (listener_addr_tx, listener_addr_rx) = oneshot::channel();
+
+		let client = async_std::task::spawn(async move {
+			let socket = TcpStream::connect(listener_addr_rx.await.unwrap()).await.unwrap();
+			let (_, mut substream) = upgrade::apply_outbound(
+				socket,
+				NotificationsOut::new(PROTO_NAME, vec![])
+					.with_compression(COMPRESSION),
+				upgrade::Version::V1
+			).await.unwrap();
+
+			assert!(substream.is_compressed());
+			// The remote might close the substream before everything has been sent.
+			let _ = substream.send(vec![0; 64 * 1024]).await;
+		});
+
+		async_std::task::block_on(async move {
+			let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+			listener_addr_tx.send(listener.local_addr().unwrap()).unwrap();
+
+			let (socket, _) = listener.accept().await.unwrap();
+			let (_, mut substream) = upgrade::apply_inbound(
+				socket,
+				NotificationsIn::new(PROTO_NAME, DEFAULT_MAX_HANDSHAKE_SIZE, 1024 * 1024)
+					.with_compression(COMPRESSION)
+			).await.unwrap();
+
+			assert_eq!(&substream.protocol_name()[..], PROTO_NAME);
+			assert!(substream.is_compressed());
+			substream.send_handshake(vec![]);
+
+			match substream.next().await {
+				Some(Err(NotificationsInError::Decompression { max })) => assert_eq!(max, 16 * 1024),
+				other => panic!("unexpected outcome: {:?}", other),
+			}
+		});
+
+		async_std::task::block_on(client);
+	}
+
+	#[test]
+	fn fallback_protocol_name() {
+		const PROTO_NAME: &'static [u8] = b"/test/proto/2";
+		const FALLBACK_PROTO_NAME: &'static [u8] = b"/test/proto/1";
+		let (listener_addr_tx, listener_addr_rx) = oneshot::channel();
+
+		let client = async_std::task::spawn(async move {
+			let socket = TcpStream::connect(listener_addr_rx.await.unwrap()).await.unwrap();
+			let (handshake, mut substream) = upgrade::apply_outbound(
+				socket,
+				NotificationsOut::new(FALLBACK_PROTO_NAME, &b"initial message"[..]),
+				upgrade::Version::V1
+			).await.unwrap();
+
+			assert_eq!(handshake, b"hello world");
+			substream.send(b"test message".to_vec()).await.unwrap();
+		});
+
+		async_std::task::block_on(async move {
+			let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+			listener_addr_tx.send(listener.local_addr().unwrap()).unwrap();
+
+			let (socket, _) = listener.accept().await.unwrap();
+			let (initial_message, mut substream) = upgrade::apply_inbound(
+				socket,
+				NotificationsIn::new(PROTO_NAME, DEFAULT_MAX_HANDSHAKE_SIZE, 1024 * 1024)
+					.with_fallback_names(vec![FALLBACK_PROTO_NAME])
+			).await.unwrap();
+
+			assert_eq!(initial_message, b"initial message");
+			assert_eq!(&substream.protocol_name()[..], FALLBACK_PROTO_NAME);
+			substream.send_handshake(&b"hello world"[..]);
+
+			let msg = substream.next().await.unwrap().unwrap();
+			assert_eq!(msg.as_ref(), b"test message");
+		});
+
+		async_std::task::block_on(client);
+	}
+
+	#[test]
+	fn keep_alive_pings_swallowed() {
+		const PROTO_NAME: &'static [u8] = b"/test/proto/1";
+		let (listener_addr_tx, listener_addr_rx) = oneshot::channel();
+		let (done_tx, mut done_rx) = oneshot::channel();
+
+		let client = async_std::task::spawn(async move {
+			let socket = TcpStream::connect(listener_addr_rx.await.unwrap()).await.unwrap();
+			let (_, mut substream) = upgrade::apply_outbound(
+				socket,
+				NotificationsOut::new(PROTO_NAME, vec![])
+					.with_keep_alive_pings(Duration::from_millis(10)),
+				upgrade::Version::V1
+			).await.unwrap();
+
+			assert!(substream.has_keep_alive_pings());
+			substream.send(vec![]).await.unwrap();
+
+			// Pings are only sent while the substream is being flushed.
+			while let Ok(None) = done_rx.try_recv() {
+				Delay::new(Duration::from_millis(5)).await;
+				substream.flush().await.unwrap();
+			}
+
+			substream.send(b"test message".to_vec()).await.unwrap();
+		});
+
+		async_std::task::block_on(async move {
+			let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+			listener_addr_tx.send(listener.local_addr().unwrap()).unwrap();
+
+			let (socket, _) = listener.accept().await.unwrap();
+			let (_, mut substream) = upgrade::apply_inbound(
+				socket,
+				NotificationsIn::new(PROTO_NAME, DEFAULT_MAX_HANDSHAKE_SIZE, 1024 * 1024)
+					.with_keep_alive_pings()
+			).await.unwrap();
+
+			assert_eq!(&substream.protocol_name()[..], PROTO_NAME);
+			assert!(substream.has_keep_alive_pings());
+			substream.send_handshake(vec![]);
+
+			// An empty notification must not be mistaken for a ping.
+			let msg = substream.next().await.unwrap().unwrap();
+			assert!(msg.is_empty());
+			let notif_received = substream.last_frame_received();
+
+			// Pings are received in the meantime, but never returned.
+			match future::select(substream.next(), Delay::new(Duration::from_millis(100))).await {
+				future::Either::Right(_) => {},
+				future::Either::Left((other, _)) => panic!("unexpected outcome: {:?}", other),
+			}
+			assert!(substream.last_frame_received() > notif_received);
+			done_tx.send(()).unwrap();
+
+			let msg = substream.next().await.unwrap().unwrap();
+			assert_eq!(msg.as_ref(), b"test message");
+		});
+
+		async_std::task::block_on(client);
+	}
+
+	#[test]
+	#[should_panic]
+	fn zero_ping_interval_rejected() {
+		let _ = NotificationsOut::new(&b"/test/proto/1"[..], vec![])
+			.with_keep_alive_pings(Duration::from_secs(0));
+	}
+
+	/// Socket on which reading and writing never make progress.
+	struct StalledSocket;
+
+	impl AsyncRead for StalledSocket {
+		fn poll_read(self: Pin<&mut Self>, _: &mut Context, _: &mut [u8]) -> Poll<io::Result<usize>> {
+			Poll::Pending
+		}
+	}
+
+	impl AsyncWrite for StalledSocket {
+		fn poll_write(self: Pin<&mut Self>, _: &mut Context, _: &[u8]) -> Poll<io::Result<usize>> {
+			Poll::Pending
+		}
+
+		fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+			Poll::Pending
+		}
+
+		fn poll_close(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+			Poll::Pending
+		}
+	}
+
+	#[test]
+	fn pending_ping_counts_against_clogging_limit() {
+		let interval = Duration::from_secs(60);
+		let mut substream = NotificationsOutSubstream {
+			socket: Framed::new(StalledSocket, UviBytes::default()),
+			messages_queue: VecDeque::new(),
+			ping_pending: false,
+			need_flush: false,
+			compression_level: None,
+			ping_timer: Some((Delay::new(interval), interval)),
+			tagged_frames: true,
+			version: None,
+		};
+
+		for _ in 0..MAX_PENDING_MESSAGES - 1 {
+			Sink::start_send(Pin::new(&mut substream), b"foo".to_vec()).unwrap();
+		}
+
+		// The ping timer has fired while the messages are waiting to be sent.
+		substream.ping_pending = true;
+		assert_eq!(substream.messages_queue.len(), MAX_PENDING_MESSAGES - 1);
+		match Sink::start_send(Pin::new(&mut substream), b"foo".to_vec()) {
+			Err(NotificationsOutError::Clogged) => {},
+			other => panic!("unexpected outcome: {:?}", other),
+		}
+	}
+
+	#[test]
+	fn pings_out_no_pings_in_falls_back() {
+		const PROTO_NAME: &'static [u8] = b"/test/proto/1";
+		let (listener_addr_tx, listener_addr_rx) = oneshot::channel();
+
+		let client = async_std::task::spawn(async move {
+			let socket = TcpStream::connect(listener_addr_rx.await.unwrap()).await.unwrap();
+			let (_, mut substream) = upgrade::apply_outbound(
+				socket,
+				NotificationsOut::new(PROTO_NAME, vec![])
+					.with_keep_alive_pings(Duration::from_millis(10)),
+				upgrade::Version::V1
+			).await.unwrap();
+
+			assert!(!substream.has_keep_alive_pings());
+			substream.send(vec![]).await.unwrap();
+			substream.send(b"test message".to_vec()).await.unwrap();
+		});
+
+		async_std::task::block_on(async move {
+			let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+			listener_addr_tx.send(listener.local_addr().unwrap()).unwrap();
+
+			let (socket, _) = listener.accept().await.unwrap();
+			let (_, mut substream) = upgrade::apply_inbound(
+				socket,
+				NotificationsIn::new(PROTO_NAME, DEFAULT_MAX_HANDSHAKE_SIZE, 1024 * 1024)
+			).await.unwrap();
+
+			assert!(!substream.has_keep_alive_pings());
+			substream.send_handshake(vec![]);
+
+			let msg = substream.next().await.unwrap().unwrap();
+			assert!(msg.is_empty());
+			let msg = substream.next().await.unwrap().unwrap();
+			assert_eq!(msg.as_ref(), b"test message");
+		});
+
+		async_std::task::block_on(client);
+	}
+
+	#[test]
+	fn no_pings_out_pings_in_falls_back() {
+		const PROTO_NAME: &'static [u8] = b"/test/proto/1";
+		let (listener_addr_tx, listener_addr_rx) = oneshot::channel();
+
+		let client = async_std::task::spawn(async move {
+			let socket = TcpStream::connect(listener_addr_rx.await.unwrap()).await.unwrap();
+			let (_, mut substream) = upgrade::apply_outbound(
+				socket,
+				NotificationsOut::new(PROTO_NAME, vec![]),
+				upgrade::Version::V1
+			).await.unwrap();
+
+			assert!(!substream.has_keep_alive_pings());
+			substream.send(vec![]).await.unwrap();
+			substream.send(b"test message".to_vec()).await.unwrap();
+		});
+
+		async_std::task::block_on(async move {
+			let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+			listener_addr_tx.send(listener.local_addr().unwrap()).unwrap();
+
+			let (socket, _) = listener.accept().await.unwrap();
+			let (_, mut substream) = upgrade::apply_inbound(
+				socket,
+				NotificationsIn::new(PROTO_NAME, DEFAULT_MAX_HANDSHAKE_SIZE, 1024 * 1024)
+					.with_keep_alive_pings()
+			).await.unwrap();
+
+			assert_eq!(&substream.protocol_name()[..], PROTO_NAME);
+			assert!(!substream.has_keep_alive_pings());
+			substream.send_handshake(vec![]);
+
+			let msg = substream.next().await.unwrap().unwrap();
+			assert!(msg.is_empty());
+			let msg = substream.next().await.unwrap().unwrap();
+			assert_eq!(msg.as_ref(), b"test message");
+		});
+
+		async_std::task::block_on(client);
+	}
+
+	#[test]
+	fn pings_and_compression() {
+		const PROTO_NAME: &'static [u8] = b"/test/proto/1";
+		let (listener_addr_tx, listener_addr_rx) = oneshot::channel();
+
+		let client = async_std::task::spawn(async move {
+			let socket = TcpStream::connect(listener_addr_rx.await.unwrap()).await.unwrap();
+			let (_, mut substream) = upgrade::apply_outbound(
+				socket,
+				NotificationsOut::new(PROTO_NAME, vec![])
+					.with_compression(COMPRESSION)
+					.with_keep_alive_pings(Duration::from_millis(10)),
+				upgrade::Version::V1
+			).await.unwrap();
+
+			assert!(substream.is_compressed());
+			assert!(substream.has_keep_alive_pings());
+			substream.send(vec![]).await.unwrap();
+			substream.send(vec![5; 4096]).await.unwrap();
+		});
+
+		async_std::task::block_on(async move {
+			let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+			listener_addr_tx.send(listener.local_addr().unwrap()).unwrap();
+
+			let (socket, _) = listener.accept().await.unwrap();
+			let (_, mut substream) = upgrade::apply_inbound(
+				socket,
+				NotificationsIn::new(PROTO_NAME, DEFAULT_MAX_HANDSHAKE_SIZE, 1024)
+					.with_compression(COMPRESSION)
+					.with_keep_alive_pings()
+			).await.unwrap();
+
+			assert_eq!(&substream.protocol_name()[..], PROTO_NAME);
+			assert!(substream.is_compressed());
+			assert!(substream.has_keep_alive_pings());
+			substream.send_handshake(vec![]);
+
+			let msg = substream.next().await.unwrap().unwrap();
+			assert!(msg.is_empty());
+			let msg = substream.next().await.unwrap().unwrap();
+			assert_eq!(msg.as_ref(), &[5; 4096][..]);
+		});
+
+		async_std::task::block_on(client);
+	}
+
+	#[test]
+	fn versioned_both_sides() {
+		let (listener_addr_tx, listener_addr_rx) = oneshot::channel();
+
+		let client = async_std::task::spawn(async move {
+			let socket = TcpStream::connect(listener_addr_rx.await.unwrap()).await.unwrap();
+			let (_, mut substream) = upgrade::apply_outbound(
+				socket,
+				NotificationsOut::new(&b"/test/proto/1"[..], vec![]).with_versions(versions()),
+				upgrade::Version::V1
+			).await.unwrap();
+
+			assert_eq!(substream.version(), Some(2));
+			substream.send(vec![]).await.unwrap();
+			substream.send(b"test message".to_vec()).await.unwrap();
+		});
+
+		async_std::task::block_on(async move {
+			let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+			listener_addr_tx.send(listener.local_addr().unwrap()).unwrap();
+
+			let (socket, _) = listener.accept().await.unwrap();
+			let (_, mut substream) = upgrade::apply_inbound(
+				socket,
+				NotificationsIn::new(&b"/test/proto/1"[..], DEFAULT_MAX_HANDSHAKE_SIZE, 1024 * 1024)
+					.with_versions(versions())
+			).await.unwrap();
+
+			assert_eq!(&substream.protocol_name()[..], b"/test/proto/2");
+			assert_eq!(substream.version(), Some(2));
+			substream.send_handshake(vec![]);
+
+			let msg = substream.next().await.unwrap().unwrap();
+			assert!(msg.is_empty());
+			let msg = substream.next().await.unwrap().unwrap();
+			assert_eq!(msg.as_ref(), b"test message");
+		});
+
+		async_std::task::block_on(client);
+	}
+
+	#[test]
+	fn versioned_out_downgrades_to_v1() {
+		const PROTO_NAME: &'static [u8] = b"/test/proto/1";
+		let (listener_addr_tx, listener_addr_rx) = oneshot::channel();
+
+		let client = async_std::task::spawn(async move {
+			let socket = TcpStream::connect(listener_addr_rx.await.unwrap()).await.unwrap();
+			let (_, mut substream) = upgrade::apply_outbound(
+				socket,
+				NotificationsOut::new(PROTO_NAME, vec![]).with_versions(versions()),
+				upgrade::Version::V1
+			).await.unwrap();
+
+			assert_eq!(substream.version(), Some(1));
+			substream.send(vec![]).await.unwrap();
+			substream.send(b"test message".to_vec()).await.unwrap();
+		});
+
+		async_std::task::block_on(async move {
+			let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+			listener_addr_tx.send(listener.local_addr().unwrap()).unwrap();
+
+			// The listener only knows about the first version of the protocol, and doesn't know
+			// about versions at all.
+			let (socket, _) = listener.accept().await.unwrap();
+			let (_, mut substream) = upgrade::apply_inbound(
+				socket,
+				NotificationsIn::new(PROTO_NAME, DEFAULT_MAX_HANDSHAKE_SIZE, 1024 * 1024)
+			).await.unwrap();
+
+			assert_eq!(&substream.protocol_name()[..], PROTO_NAME);
+			assert_eq!(substream.version(), None);
+			substream.send_handshake(vec![]);
+
+			let msg = substream.next().await.unwrap().unwrap();
+			assert!(msg.is_empty());
+			let msg = substream.next().await.unwrap().unwrap();
+			assert_eq!(msg.as_ref(), b"test message");
+		});
+
+		async_std::task::block_on(client);
+	}
+
+	#[test]
+	fn v1_out_accepted_by_versioned_in() {
+		const PROTO_NAME: &'static [u8] = b"/test/proto/1";
+		let (listener_addr_tx, listener_addr_rx) = oneshot::channel();
+
+		let client = async_std::task::spawn(async move {
+			let socket = TcpStream::connect(listener_addr_rx.await.unwrap()).await.unwrap();
+			let (_, mut substream) = upgrade::apply_outbound(
+				socket,
+				NotificationsOut::new(PROTO_NAME, vec![]),
+				upgrade::Version::V1
+			).await.unwrap();
+
+			assert_eq!(substream.version(), None);
+			substream.send(vec![]).await.unwrap();
+			substream.send(b"test message".to_vec()).await.unwrap();
+		});
+
+		async_std::task::block_on(async move {
+			let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+			listener_addr_tx.send(listener.local_addr().unwrap()).unwrap();
+
+			let (socket, _) = listener.accept().await.unwrap();
+			let (_, mut substream) = upgrade::apply_inbound(
+				socket,
+				NotificationsIn::new(PROTO_NAME, DEFAULT_MAX_HANDSHAKE_SIZE, 1024 * 1024)
+					.with_versions(versions())
+			).await.unwrap();
+
+			assert_eq!(&substream.protocol_name()[..], PROTO_NAME);
+			assert_eq!(substream.version(), Some(1));
+			substream.send_handshake(vec![]);
+
+			let msg = substream.next().await.unwrap().unwrap();
+			assert!(msg.is_empty());
+			let msg = substream.next().await.unwrap().unwrap();
+			assert_eq!(msg.as_ref(), b"test message");
+		});
+
+		async_std::task::block_on(client);
 	}
 }
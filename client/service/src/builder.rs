@@ -887,6 +887,14 @@ ServiceBuilder<
 		let block_announce_validator =
 			Box::new(sp_consensus::block_validation::DefaultBlockAnnounceValidator::new(client.clone()));
 
+		// The Prometheus registry is created before the network, so that it can register its own
+		// metrics in it.
+		let prometheus_registry = match (config.prometheus_port, prometheus_registry) {
+			(Some(_), Some(registry)) => Some(registry),
+			(Some(_), None) => Some(Registry::new_custom(Some("substrate".into()), None)?),
+			(None, _) => None,
+		};
+
 		let network_params = sc_network::config::Params {
 			roles: config.roles,
 			executor: {
@@ -906,6 +914,7 @@ ServiceBuilder<
 			import_queue,
 			protocol_id,
 			block_announce_validator,
+			metrics_registry: prometheus_registry.clone(),
 		};
 
 		let has_bootnodes = !network_params.network_config.boot_nodes.is_empty();
@@ -1021,12 +1030,9 @@ ServiceBuilder<
 		}
 
 		// Prometheus endpoint and metrics
-		let metrics = if let Some(port) = config.prometheus_port {
-			let registry = match prometheus_registry {
-				Some(registry) => registry,
-				None => Registry::new_custom(Some("substrate".into()), None)?
-			};
-
+		let metrics = if let (Some(port), Some(registry)) =
+			(config.prometheus_port, prometheus_registry)
+		{
 			let metrics = ServiceMetrics::register(&registry)?;
 
 			let future = select(
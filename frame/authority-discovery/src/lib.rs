@@ -121,6 +121,8 @@ mod tests {
 		type ValidatorId = AuthorityId;
 		type ValidatorIdOf = ConvertInto;
 		type DisabledValidatorsThreshold = DisabledValidatorsThreshold;
+		type SessionHistoryDepth = ();
+		type ValidatorHistoryDepth = ();
 	}
 
 	impl pallet_session::historical::Trait for Test {
@@ -84,6 +84,8 @@ impl pallet_session::Trait for Test {
 	type ValidatorIdOf = ();
 	type Keys = MockSessionKeys;
 	type DisabledValidatorsThreshold = DisabledValidatorsThreshold;
+	type SessionHistoryDepth = ();
+	type ValidatorHistoryDepth = ();
 }
 
 impl pallet_timestamp::Trait for Test {
@@ -138,6 +138,8 @@ impl pallet_session::Trait for Runtime {
 	type Keys = UintAuthorityId;
 	type Event = ();
 	type DisabledValidatorsThreshold = DisabledValidatorsThreshold;
+	type SessionHistoryDepth = ();
+	type ValidatorHistoryDepth = ();
 }
 
 impl pallet_session::historical::Trait for Runtime {
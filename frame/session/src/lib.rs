@@ -169,6 +169,26 @@ impl<A> SessionManager<A> for () {
 	fn end_session(_: SessionIndex) {}
 }
 
+/// Every manager of the tuple is notified in order. The validator set returned by `new_session`
+/// is the one of the first manager returning `Some`, the others are ignored.
+#[impl_trait_for_tuples::impl_for_tuples(1, 30)]
+impl<A> SessionManager<A> for Tuple {
+	fn new_session(new_index: SessionIndex) -> Option<Vec<A>> {
+		let mut new_validators = None;
+		for_tuples!(
+			#(
+				let validators = Tuple::new_session(new_index);
+				new_validators = new_validators.or(validators);
+			)*
+		);
+		new_validators
+	}
+
+	fn end_session(end_index: SessionIndex) {
+		for_tuples!( #( Tuple::end_session(end_index); )* )
+	}
+}
+
 /// Handler for session life cycle events.
 pub trait SessionHandler<ValidatorId> {
 	/// All the key type ids this session handler can process.
@@ -320,6 +340,9 @@ pub trait Trait: frame_system::Trait {
 	type ShouldEndSession: ShouldEndSession<Self::BlockNumber>;
 
 	/// Handler for managing new session.
+	///
+	/// Several managers can be notified by using a tuple, see the tuple implementation of
+	/// `SessionManager` for which validator set is used.
 	type SessionManager: SessionManager<Self::ValidatorId>;
 
 	/// Handler when a session has changed.
@@ -333,6 +356,17 @@ pub trait Trait: frame_system::Trait {
 	/// After the threshold is reached `disabled` method starts to return true,
 	/// which in combination with `pallet_staking` forces a new era.
 	type DisabledValidatorsThreshold: Get<Perbill>;
+
+	/// The number of sessions, including the current one, for which the start block is kept.
+	///
+	/// `0` disables the session history.
+	type SessionHistoryDepth: Get<SessionIndex>;
+
+	/// The number of sessions, including the current one, for which the validator set is kept.
+	///
+	/// Every kept session stores a full copy of its validator set. `0` disables the validator
+	/// history.
+	type ValidatorHistoryDepth: Get<SessionIndex>;
 }
 
 const DEDUP_KEY_PREFIX: &[u8] = b":session:keys";
@@ -373,6 +407,20 @@ decl_storage! {
 		// TODO: Migrate to a normal map now https://github.com/paritytech/substrate/issues/4917
 		KeyOwner: double_map hasher(twox_64_concat) Vec<u8>, hasher(blake2_256) (KeyTypeId, Vec<u8>)
 			=> Option<T::ValidatorId>;
+
+		/// The block at which a session started.
+		///
+		/// Only the last `SessionHistoryDepth` sessions are kept, older entries are removed as
+		/// sessions rotate.
+		SessionStartBlock get(fn start_of): map hasher(twox_64_concat) SessionIndex
+			=> Option<T::BlockNumber>;
+
+		/// The validator set of a session.
+		///
+		/// Only the last `ValidatorHistoryDepth` sessions are kept, older entries are removed as
+		/// sessions rotate.
+		HistoricalValidators get(fn historical_validators): map hasher(twox_64_concat) SessionIndex
+			=> Option<Vec<T::ValidatorId>>;
 	}
 	add_extra_genesis {
 		config(keys): Vec<(T::AccountId, T::ValidatorId, T::Keys)>;
@@ -421,8 +469,16 @@ decl_storage! {
 			// Tell everyone about the genesis session keys
 			T::SessionHandler::on_genesis_session::<T::Keys>(&queued_keys);
 
+			if T::ValidatorHistoryDepth::get() > 0 {
+				<HistoricalValidators<T>>::insert(0, &initial_validators_0);
+			}
+
 			<Validators<T>>::put(initial_validators_0);
 			<QueuedKeys<T>>::put(queued_keys);
+
+			if T::SessionHistoryDepth::get() > 0 {
+				<SessionStartBlock<T>>::insert(0, T::BlockNumber::zero());
+			}
 		});
 	}
 }
@@ -538,6 +594,23 @@ impl<T: Trait> Module<T> {
 		let session_index = session_index + 1;
 		CurrentIndex::put(session_index);
 
+		// Record the start of the new session and forget the one falling out of the history.
+		let history_depth = T::SessionHistoryDepth::get();
+		if history_depth > 0 {
+			<SessionStartBlock<T>>::insert(session_index, <system::Module<T>>::block_number());
+			if let Some(expired) = session_index.checked_sub(history_depth) {
+				<SessionStartBlock<T>>::remove(expired);
+			}
+		}
+
+		let validator_history_depth = T::ValidatorHistoryDepth::get();
+		if validator_history_depth > 0 {
+			<HistoricalValidators<T>>::insert(session_index, &validators);
+			if let Some(expired) = session_index.checked_sub(validator_history_depth) {
+				<HistoricalValidators<T>>::remove(expired);
+			}
+		}
+
 		// Get next validator set.
 		let maybe_next_validators = T::SessionManager::new_session(session_index + 1);
 		let (next_validators, next_identities_changed)
@@ -595,6 +668,45 @@ impl<T: Trait> Module<T> {
 		);
 	}
 
+	/// The session which contains the block `block`.
+	///
+	/// Returns `None` if `block` is in the future or if its session is older than the
+	/// `SessionHistoryDepth` last sessions.
+	pub fn session_of(block: T::BlockNumber) -> Option<SessionIndex> {
+		if block > <system::Module<T>>::block_number() {
+			return None;
+		}
+
+		let current = CurrentIndex::get();
+		let oldest = current.saturating_add(1).saturating_sub(T::SessionHistoryDepth::get());
+
+		// Start blocks are increasing, so binary search for the last session which started at or
+		// before `block`. Missing entries can only be the oldest ones of the range and are treated
+		// as such, which keeps the predicate monotonic.
+		let (mut low, mut high) = (oldest, current.saturating_add(1));
+		while low < high {
+			let mid = low + (high - low) / 2;
+			if Self::start_of(mid).map_or(true, |start| start <= block) {
+				low = mid + 1;
+			} else {
+				high = mid;
+			}
+		}
+
+		match low.checked_sub(1) {
+			// The history doesn't go further back.
+			Some(session) if session >= oldest => Self::start_of(session).map(|_| session),
+			_ => None,
+		}
+	}
+
+	/// Whether `who` was in the validator set of `session`.
+	///
+	/// Returns `false` if `session` is not part of the `ValidatorHistoryDepth` last sessions.
+	pub fn was_validator(session: SessionIndex, who: &T::ValidatorId) -> bool {
+		Self::historical_validators(session).map_or(false, |validators| validators.contains(who))
+	}
+
 	/// Disable the validator of index `i`.
 	///
 	/// Returns `true` if this causes a `DisabledValidatorsThreshold` of validators
@@ -1022,6 +1134,116 @@ mod tests {
 		});
 	}
 
+	#[test]
+	fn session_start_blocks_are_recorded_and_pruned() {
+		new_test_ext().execute_with(|| {
+			assert_eq!(Session::start_of(0), Some(0));
+			assert_eq!(Session::session_of(0), Some(0));
+
+			initialize_block(1);
+			initialize_block(2);
+			initialize_block(3);
+			assert_eq!(Session::current_index(), 1);
+			assert_eq!(Session::start_of(1), Some(2));
+			assert_eq!(Session::session_of(1), Some(0));
+			assert_eq!(Session::session_of(2), Some(1));
+			assert_eq!(Session::session_of(3), Some(1));
+			// blocks in the future don't belong to any session yet.
+			assert_eq!(Session::session_of(4), None);
+
+			initialize_block(4);
+			assert_eq!(Session::start_of(0), Some(0));
+			assert_eq!(Session::session_of(0), Some(0));
+
+			// the history depth is 3, so session 0 is forgotten.
+			initialize_block(6);
+			assert_eq!(Session::current_index(), 3);
+			assert_eq!(Session::start_of(0), None);
+			assert_eq!(Session::session_of(0), None);
+			assert_eq!(Session::session_of(1), None);
+			assert_eq!(Session::session_of(2), Some(1));
+			assert_eq!(Session::session_of(5), Some(2));
+			assert_eq!(Session::session_of(6), Some(3));
+		});
+	}
+
+	#[test]
+	fn historical_validators_are_recorded_and_pruned() {
+		new_test_ext().execute_with(|| {
+			assert_eq!(Session::historical_validators(0), Some(vec![1, 2, 3]));
+
+			set_next_validators(vec![1, 2, 3, 4]);
+			initialize_block(2);
+			assert_eq!(Session::historical_validators(1), Some(vec![1, 2, 3]));
+			assert!(Session::was_validator(1, &3));
+			assert!(!Session::was_validator(1, &4));
+
+			// the history depth is 2, so session 0 is forgotten.
+			initialize_block(4);
+			assert_eq!(Session::historical_validators(2), Some(vec![1, 2, 3, 4]));
+			assert!(Session::was_validator(2, &4));
+			assert!(Session::was_validator(1, &1));
+			assert_eq!(Session::historical_validators(0), None);
+			assert!(!Session::was_validator(0, &1));
+
+			// the set of a session that didn't happen yet is unknown.
+			assert!(!Session::was_validator(3, &1));
+		});
+	}
+
+	#[test]
+	fn tuple_session_manager_first_validator_set_wins() {
+		use std::cell::RefCell;
+
+		thread_local! {
+			static CALLS: RefCell<Vec<(&'static str, SessionIndex)>> = RefCell::new(vec![]);
+		}
+
+		struct NoChange;
+		impl SessionManager<u64> for NoChange {
+			fn new_session(i: SessionIndex) -> Option<Vec<u64>> {
+				CALLS.with(|c| c.borrow_mut().push(("new_none", i)));
+				None
+			}
+			fn end_session(i: SessionIndex) {
+				CALLS.with(|c| c.borrow_mut().push(("end_none", i)));
+			}
+		}
+
+		struct SetOf<V: Get<u64>>(PhantomData<V>);
+		impl<V: Get<u64>> SessionManager<u64> for SetOf<V> {
+			fn new_session(i: SessionIndex) -> Option<Vec<u64>> {
+				CALLS.with(|c| c.borrow_mut().push(("new_some", i)));
+				Some(vec![V::get()])
+			}
+			fn end_session(i: SessionIndex) {
+				CALLS.with(|c| c.borrow_mut().push(("end_some", i)));
+			}
+		}
+
+		struct One;
+		impl Get<u64> for One {
+			fn get() -> u64 { 1 }
+		}
+		struct Two;
+		impl Get<u64> for Two {
+			fn get() -> u64 { 2 }
+		}
+
+		type Managers = (NoChange, SetOf<One>, SetOf<Two>);
+
+		assert_eq!(<Managers as SessionManager<u64>>::new_session(5), Some(vec![1]));
+		<Managers as SessionManager<u64>>::end_session(4);
+		assert_eq!(<(NoChange, NoChange) as SessionManager<u64>>::new_session(6), None);
+
+		// every manager is notified, in order.
+		assert_eq!(CALLS.with(|c| c.borrow().clone()), vec![
+			("new_none", 5), ("new_some", 5), ("new_some", 5),
+			("end_none", 4), ("end_some", 4), ("end_some", 4),
+			("new_none", 6), ("new_none", 6),
+		]);
+	}
+
 	#[test]
 	fn return_true_if_more_than_third_is_disabled() {
 		new_test_ext().execute_with(|| {
@@ -189,6 +189,8 @@ impl pallet_timestamp::Trait for Test {
 
 parameter_types! {
 	pub const DisabledValidatorsThreshold: Perbill = Perbill::from_percent(33);
+	pub const SessionHistoryDepth: SessionIndex = 3;
+	pub const ValidatorHistoryDepth: SessionIndex = 2;
 }
 
 impl Trait for Test {
@@ -203,6 +205,8 @@ impl Trait for Test {
 	type Keys = MockSessionKeys;
 	type Event = ();
 	type DisabledValidatorsThreshold = DisabledValidatorsThreshold;
+	type SessionHistoryDepth = SessionHistoryDepth;
+	type ValidatorHistoryDepth = ValidatorHistoryDepth;
 }
 
 #[cfg(feature = "historical")]
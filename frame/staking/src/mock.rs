@@ -164,6 +164,8 @@ impl pallet_session::Trait for Test {
 	type SessionHandler = TestSessionHandler;
 	type Keys = UintAuthorityId;
 	type DisabledValidatorsThreshold = DisabledValidatorsThreshold;
+	type SessionHistoryDepth = ();
+	type ValidatorHistoryDepth = ();
 }
 
 impl pallet_session::historical::Trait for Test {
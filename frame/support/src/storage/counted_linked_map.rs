@@ -0,0 +1,322 @@
+// Copyright 2020 Parity Technologies (UK) Ltd.
+// This file is part of Substrate.
+
+// Substrate is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Substrate is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Substrate.  If not, see <http://www.gnu.org/licenses/>.
+
+//! A linked map which maintains the number of its keys in a separate storage value.
+
+use sp_std::marker::PhantomData;
+use codec::{FullCodec, Decode, EncodeLike, Ref};
+use crate::{storage::{StorageLinkedMap, StorageValue}, traits::Len};
+
+/// A wrapper around a `linked_map` keeping track of how many keys it holds.
+///
+/// `Map` is a `linked_map` declared with `decl_storage!` and `Counter` is a `u32` storage value
+/// declared next to it. The counter is updated on every operation which adds or removes a key,
+/// including `mutate` calls that implicitly insert a value, so `count` never needs to iterate.
+///
+/// All accesses to `Map` must go through this wrapper, otherwise the counter gets out of sync.
+///
+/// ```nocompile
+/// decl_storage! {
+/// 	trait Store for Module<T: Trait> as Example {
+/// 		Items: linked_map hasher(blake2_256) u32 => u64;
+/// 		ItemCount: u32;
+/// 	}
+/// }
+///
+/// type CountedItems = CountedLinkedMap<u32, u64, Items, ItemCount>;
+/// ```
+pub struct CountedLinkedMap<K, V, Map, Counter>(PhantomData<(K, V, Map, Counter)>);
+
+impl<K, V, Map, Counter> CountedLinkedMap<K, V, Map, Counter>
+where
+	K: FullCodec,
+	V: FullCodec,
+	Map: StorageLinkedMap<K, V>,
+	Counter: StorageValue<u32, Query = u32>,
+{
+	/// The number of keys in the map.
+	pub fn count() -> u32 {
+		Counter::get()
+	}
+}
+
+fn increment<Counter: StorageValue<u32, Query = u32>>() {
+	Counter::mutate(|c| *c = c.saturating_add(1));
+}
+
+fn decrement<Counter: StorageValue<u32, Query = u32>>() {
+	Counter::mutate(|c| *c = c.saturating_sub(1));
+}
+
+/// A draining iterator which decrements the counter for every element it removes.
+pub struct CountedDrainer<I, Counter> {
+	inner: I,
+	_phantom: PhantomData<Counter>,
+}
+
+impl<I, Counter> Iterator for CountedDrainer<I, Counter>
+where
+	I: Iterator,
+	Counter: StorageValue<u32, Query = u32>,
+{
+	type Item = I::Item;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		let item = self.inner.next()?;
+		decrement::<Counter>();
+		Some(item)
+	}
+}
+
+impl<K, V, Map, Counter> StorageLinkedMap<K, V> for CountedLinkedMap<K, V, Map, Counter>
+where
+	K: FullCodec,
+	V: FullCodec,
+	Map: StorageLinkedMap<K, V>,
+	Counter: StorageValue<u32, Query = u32>,
+{
+	type Query = Map::Query;
+
+	type Enumerator = Map::Enumerator;
+
+	type Drainer = CountedDrainer<Map::Drainer, Counter>;
+
+	fn contains_key<KeyArg: EncodeLike<K>>(key: KeyArg) -> bool {
+		Map::contains_key(key)
+	}
+
+	fn get<KeyArg: EncodeLike<K>>(key: KeyArg) -> Self::Query {
+		Map::get(key)
+	}
+
+	fn swap<KeyArg1: EncodeLike<K>, KeyArg2: EncodeLike<K>>(key1: KeyArg1, key2: KeyArg2) {
+		// Swapping never changes the number of keys.
+		Map::swap(key1, key2)
+	}
+
+	fn insert<KeyArg: EncodeLike<K>, ValArg: EncodeLike<V>>(key: KeyArg, val: ValArg) {
+		if !Map::contains_key(Ref::from(&key)) {
+			increment::<Counter>();
+		}
+		Map::insert(key, val)
+	}
+
+	fn remove<KeyArg: EncodeLike<K>>(key: KeyArg) {
+		if Map::contains_key(Ref::from(&key)) {
+			decrement::<Counter>();
+		}
+		Map::remove(key)
+	}
+
+	fn mutate<KeyArg: EncodeLike<K>, R, F: FnOnce(&mut Self::Query) -> R>(key: KeyArg, f: F) -> R {
+		let existed = Map::contains_key(Ref::from(&key));
+		let ret = Map::mutate(Ref::from(&key), f);
+		match (existed, Map::contains_key(key)) {
+			(false, true) => increment::<Counter>(),
+			(true, false) => decrement::<Counter>(),
+			_ => (),
+		}
+		ret
+	}
+
+	fn take<KeyArg: EncodeLike<K>>(key: KeyArg) -> Self::Query {
+		if Map::contains_key(Ref::from(&key)) {
+			decrement::<Counter>();
+		}
+		Map::take(key)
+	}
+
+	fn head() -> Option<K> {
+		Map::head()
+	}
+
+	fn enumerate() -> Self::Enumerator {
+		Map::enumerate()
+	}
+
+	fn enumerate_from<KeyArg: EncodeLike<K>>(key: KeyArg) -> Self::Enumerator {
+		Map::enumerate_from(key)
+	}
+
+	fn drain() -> Self::Drainer {
+		CountedDrainer {
+			inner: Map::drain(),
+			_phantom: Default::default(),
+		}
+	}
+
+	fn remove_all() {
+		Map::remove_all();
+		Counter::kill();
+	}
+
+	fn decode_len<KeyArg: EncodeLike<K>>(key: KeyArg) -> Result<usize, &'static str>
+		where V: codec::DecodeLength + Len
+	{
+		Map::decode_len(key)
+	}
+
+	/// Keys are translated one to one, so the counter is left untouched on success. On failure
+	/// the map is truncated at the first key which could not be migrated, and the counter is
+	/// recomputed by enumerating what is left.
+	fn translate<K2, V2, TK, TV>(translate_key: TK, translate_val: TV) -> Result<(), Option<K2>>
+		where K2: FullCodec + Clone, V2: Decode, TK: Fn(K2) -> K, TV: Fn(V2) -> V
+	{
+		Map::translate(translate_key, translate_val).map_err(|e| {
+			Counter::put(Map::enumerate().count() as u32);
+			e
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use sp_io::TestExternalities;
+	use crate::storage::unhashed;
+	use super::CountedLinkedMap;
+
+	struct Runtime {}
+	pub trait Trait {
+		type Origin;
+		type BlockNumber;
+	}
+
+	impl Trait for Runtime {
+		type Origin = u32;
+		type BlockNumber = u32;
+	}
+
+	decl_module! {
+		pub struct Module<T: Trait> for enum Call where origin: T::Origin {}
+	}
+
+	crate::decl_storage! {
+		trait Store for Module<T: Trait> as Runtime {
+			Items: linked_map hasher(blake2_256) u32 => u64;
+			ItemCount: u32;
+			OptionItems: linked_map hasher(blake2_256) u32 => Option<u64>;
+			OptionItemCount: u32;
+		}
+	}
+
+	type Counted = CountedLinkedMap<u32, u64, Items, ItemCount>;
+	type CountedOption = CountedLinkedMap<u32, u64, OptionItems, OptionItemCount>;
+
+	#[test]
+	fn counted_linked_map_insert_and_remove() {
+		TestExternalities::default().execute_with(|| {
+			assert_eq!(Counted::count(), 0);
+
+			Counted::insert(1, 10);
+			Counted::insert(2, 20);
+			assert_eq!(Counted::count(), 2);
+
+			// overwriting doesn't change the count.
+			Counted::insert(1, 11);
+			assert_eq!(Counted::count(), 2);
+
+			// removing a nonexistent key doesn't change the count.
+			Counted::remove(3);
+			assert_eq!(Counted::take(3), 0);
+			assert_eq!(Counted::count(), 2);
+
+			assert_eq!(Counted::take(1), 11);
+			assert_eq!(Counted::count(), 1);
+			Counted::remove(2);
+			assert_eq!(Counted::count(), 0);
+			assert_eq!(Counted::enumerate().count(), 0);
+		})
+	}
+
+	#[test]
+	fn counted_linked_map_mutate() {
+		TestExternalities::default().execute_with(|| {
+			// a value query map inserts the default value on mutate.
+			Counted::mutate(1, |v| assert_eq!(*v, 0));
+			assert!(Counted::contains_key(1));
+			assert_eq!(Counted::count(), 1);
+
+			Counted::mutate(1, |v| *v += 1);
+			assert_eq!(Counted::count(), 1);
+
+			// an option query map inserts and removes depending on the mutation.
+			CountedOption::mutate(1, |v| assert!(v.is_none()));
+			assert_eq!(CountedOption::count(), 0);
+
+			CountedOption::mutate(1, |v| *v = Some(1));
+			CountedOption::mutate(2, |v| *v = Some(2));
+			assert_eq!(CountedOption::count(), 2);
+
+			CountedOption::mutate(1, |v| *v = None);
+			assert!(!CountedOption::contains_key(1));
+			assert_eq!(CountedOption::count(), 1);
+		})
+	}
+
+	#[test]
+	fn counted_linked_map_swap_and_drain() {
+		TestExternalities::default().execute_with(|| {
+			for i in 0..5 {
+				Counted::insert(i, i as u64);
+			}
+
+			Counted::swap(0, 1);
+			Counted::swap(4, 10);
+			assert_eq!(Counted::count(), 5);
+			assert_eq!(Counted::count() as usize, Counted::enumerate().count());
+
+			assert_eq!(Counted::drain().take(2).count(), 2);
+			assert_eq!(Counted::count(), 3);
+			assert_eq!(Counted::drain().count(), 3);
+			assert_eq!(Counted::count(), 0);
+		})
+	}
+
+	#[test]
+	fn counted_linked_map_remove_all() {
+		TestExternalities::default().execute_with(|| {
+			for i in 0..5 {
+				Counted::insert(i, i as u64);
+			}
+
+			Counted::remove_all();
+			assert_eq!(Counted::count(), 0);
+			assert_eq!(Counted::enumerate().count(), 0);
+
+			Counted::insert(1, 1);
+			assert_eq!(Counted::count(), 1);
+		})
+	}
+
+	#[test]
+	fn counted_linked_map_failed_translate_recomputes_count() {
+		TestExternalities::default().execute_with(|| {
+			for i in 0..5 {
+				Counted::insert(i, i as u64);
+			}
+
+			// corrupt a value in the middle of the map, translation stops there.
+			let final_key =
+				<Items as crate::storage::generator::StorageLinkedMap<u32, u64>>
+					::storage_linked_map_final_key(2);
+			unhashed::put_raw(&final_key, &[1]);
+
+			assert_eq!(Counted::translate(|k: u32| k, |v: u64| v), Err(Some(2)));
+			assert_eq!(Counted::enumerate().map(|(k, _)| k).collect::<Vec<_>>(), vec![4, 3]);
+			assert_eq!(Counted::count(), 2);
+		})
+	}
+}
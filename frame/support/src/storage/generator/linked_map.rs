@@ -103,6 +103,16 @@ pub trait KeyFormat {
 		final_key
 	}
 
+	/// Generate the prefix shared by the full keys of all the elements of the linked map.
+	///
+	/// If `storage_linked_map_final_key` is overridden, this must be overridden accordingly.
+	fn storage_linked_map_final_prefix() -> Vec<u8> {
+		[
+			Twox128::hash(Self::module_prefix()),
+			Twox128::hash(Self::storage_prefix()),
+		].concat()
+	}
+
 	/// Generate the full key used in top storage to store the head of the linked map.
 	fn storage_linked_map_final_head_key() -> Vec<u8> {
 		[
@@ -190,6 +200,61 @@ where
 	}
 }
 
+/// A key-value pair iterator for enumerable map which removes elements as it yields them.
+///
+/// The head is read again from storage on every call to `next`, so the map may be modified
+/// between two calls.
+pub struct Drainer<K, V, F> {
+	_phantom: PhantomData<(K, V, F)>,
+}
+
+impl<K, V, F> Iterator for Drainer<K, V, F>
+where
+	K: FullCodec,
+	V: FullCodec,
+	F: KeyFormat,
+{
+	type Item = (K, V);
+
+	fn next(&mut self) -> Option<Self::Item> {
+		let head = read_head::<K, F>()?;
+
+		let (val, linkage): (V, Linkage<K>) = {
+			let head_full_key = F::storage_linked_map_final_key(&head);
+			match unhashed::take(head_full_key.as_ref()) {
+				Some(value) => value,
+				None => {
+					// TODO #3700: error should be handleable.
+					runtime_print!(
+						"ERROR: Corrupted state: linked map {:?}{:?}: \
+						head value doesn't exist at {:?}",
+						F::module_prefix(), F::storage_prefix(), head_full_key,
+					);
+					return None
+				}
+			}
+		};
+
+		// The drained element is always the head, so its successor becomes the new head.
+		if let Some(next_key) = linkage.next.as_ref().map(|k| F::storage_linked_map_final_key(k)) {
+			if let Some(mut res) = read_with_linkage::<K, V>(next_key.as_ref()) {
+				res.1.previous = None;
+				unhashed::put(next_key.as_ref(), &res);
+			} else {
+				// TODO #3700: error should be handleable.
+				runtime_print!(
+					"ERROR: Corrupted state: linked map {:?}{:?}: \
+					next value doesn't exist at {:?}",
+					F::module_prefix(), F::storage_prefix(), next_key,
+				);
+			}
+		}
+		write_head::<&K, K, F>(linkage.next.as_ref());
+
+		Some((head, val))
+	}
+}
+
 /// Update linkage when this element is removed.
 ///
 /// Takes care of updating previous and next elements points
@@ -326,6 +391,8 @@ where
 
 	type Enumerator = Enumerator<K, V, G::KeyFormat>;
 
+	type Drainer = Drainer<K, V, G::KeyFormat>;
+
 	fn contains_key<KeyArg: EncodeLike<K>>(key: KeyArg) -> bool {
 		unhashed::exists(Self::storage_linked_map_final_key(key).as_ref())
 	}
@@ -414,10 +481,35 @@ where
 		}
 	}
 
+	fn enumerate_from<KeyArg: EncodeLike<K>>(key: KeyArg) -> Self::Enumerator {
+		let final_key = Self::storage_linked_map_final_key(key);
+		let next = match read_with_linkage::<K, V>(final_key.as_ref()) {
+			Some((_data, linkage)) => linkage.next,
+			None => read_head::<_, G::KeyFormat>(),
+		};
+
+		Enumerator::<_, _, G::KeyFormat> {
+			next,
+			_phantom: Default::default(),
+		}
+	}
+
+	fn drain() -> Self::Drainer {
+		Drainer::<_, _, G::KeyFormat> {
+			_phantom: Default::default(),
+		}
+	}
+
 	fn head() -> Option<K> {
 		read_head::<_, G::KeyFormat>()
 	}
 
+	fn remove_all() {
+		let prefix = <G::KeyFormat as KeyFormat>::storage_linked_map_final_prefix();
+		sp_io::storage::clear_prefix(prefix.as_ref());
+		write_head::<&K, K, G::KeyFormat>(None);
+	}
+
 	fn decode_len<KeyArg: EncodeLike<K>>(key: KeyArg) -> Result<usize, &'static str>
 		where V: codec::DecodeLength + Len
 	{
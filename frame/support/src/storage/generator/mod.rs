@@ -28,7 +28,7 @@ mod map;
 mod double_map;
 mod value;
 
-pub use linked_map::{StorageLinkedMap, Enumerator, Linkage, KeyFormat as LinkedMapKeyFormat};
+pub use linked_map::{StorageLinkedMap, Enumerator, Drainer, Linkage, KeyFormat as LinkedMapKeyFormat};
 pub use map::StorageMap;
 pub use double_map::StorageDoubleMap;
 pub use value::StorageValue;
@@ -127,4 +127,132 @@ mod tests {
 			);
 		})
 	}
+
+	#[test]
+	fn linked_map_enumerate_from_works() {
+		let t = GenesisConfig::default().build_storage().unwrap();
+		TestExternalities::new(t).execute_with(|| {
+			let key = |i: u32| NumberNumber { a: i, b: i };
+			for i in 0u32..10u32 {
+				NumberMap::insert(key(i), i as u64);
+			}
+
+			assert_eq!(NumberMap::head(), Some(key(9)));
+
+			// resume strictly after the cursor.
+			assert_eq!(
+				NumberMap::enumerate_from(key(5)).map(|(_, v)| v).collect::<Vec<_>>(),
+				vec![4, 3, 2, 1, 0],
+			);
+
+			// nothing comes after the last element.
+			assert_eq!(NumberMap::enumerate_from(key(0)).count(), 0);
+
+			// a removed cursor falls back to the head.
+			NumberMap::remove(key(5));
+			assert_eq!(
+				NumberMap::enumerate_from(key(5)).map(|(_, v)| v).collect::<Vec<_>>(),
+				vec![9, 8, 7, 6, 4, 3, 2, 1, 0],
+			);
+		})
+	}
+
+	#[test]
+	fn linked_map_drain_works() {
+		let t = GenesisConfig::default().build_storage().unwrap();
+		TestExternalities::new(t).execute_with(|| {
+			let key = |i: u32| NumberNumber { a: i, b: i };
+			for i in 0u32..10u32 {
+				NumberMap::insert(key(i), i as u64);
+			}
+
+			// drain part of the map and check the rest is still correctly linked.
+			assert_eq!(
+				NumberMap::drain().take(3).map(|(_, v)| v).collect::<Vec<_>>(),
+				vec![9, 8, 7],
+			);
+			assert_eq!(NumberMap::head(), Some(key(6)));
+			assert!(!NumberMap::contains_key(key(7)));
+			assert_eq!(
+				NumberMap::enumerate().map(|(_, v)| v).collect::<Vec<_>>(),
+				vec![6, 5, 4, 3, 2, 1, 0],
+			);
+
+			// take a middle element, then insert a new head.
+			assert_eq!(NumberMap::take(key(3)), 3);
+			NumberMap::insert(key(42), 42);
+			assert_eq!(
+				NumberMap::enumerate().map(|(_, v)| v).collect::<Vec<_>>(),
+				vec![42, 6, 5, 4, 2, 1, 0],
+			);
+
+			// drain the rest.
+			assert_eq!(
+				NumberMap::drain().map(|(_, v)| v).collect::<Vec<_>>(),
+				vec![42, 6, 5, 4, 2, 1, 0],
+			);
+			assert_eq!(NumberMap::head(), None);
+			assert_eq!(NumberMap::enumerate().count(), 0);
+
+			// the drained map can be used again.
+			NumberMap::insert(key(1), 1);
+			assert_eq!(NumberMap::enumerate().collect::<Vec<_>>(), vec![(key(1), 1)]);
+		})
+	}
+
+	#[test]
+	fn linked_map_drain_with_mutation_works() {
+		let t = GenesisConfig::default().build_storage().unwrap();
+		TestExternalities::new(t).execute_with(|| {
+			let key = |i: u32| NumberNumber { a: i, b: i };
+			for i in 0u32..5u32 {
+				NumberMap::insert(key(i), i as u64);
+			}
+
+			let mut drain = NumberMap::drain();
+			assert_eq!(drain.next(), Some((key(4), 4)));
+
+			// remove the next element and insert a new head in the middle of the drain.
+			assert_eq!(NumberMap::take(key(3)), 3);
+			NumberMap::insert(key(42), 42);
+			assert_eq!(drain.next(), Some((key(42), 42)));
+
+			// remove the element that just became the head.
+			assert_eq!(NumberMap::take(key(2)), 2);
+			assert_eq!(drain.map(|(_, v)| v).collect::<Vec<_>>(), vec![1, 0]);
+
+			assert_eq!(NumberMap::head(), None);
+			assert_eq!(NumberMap::enumerate().count(), 0);
+			assert!(!NumberMap::contains_key(key(3)));
+			assert!(!NumberMap::contains_key(key(42)));
+		})
+	}
+
+	#[test]
+	fn linked_map_remove_all_works() {
+		let t = GenesisConfig::default().build_storage().unwrap();
+		TestExternalities::new(t).execute_with(|| {
+			let key = |i: u32| NumberNumber { a: i, b: i };
+			for i in 0u32..10u32 {
+				NumberMap::insert(key(i), i as u64);
+			}
+			Value::put((1, 2));
+
+			NumberMap::remove_all();
+
+			assert_eq!(NumberMap::head(), None);
+			assert_eq!(NumberMap::enumerate().count(), 0);
+			assert!((0u32..10u32).all(|i| !NumberMap::contains_key(key(i))));
+			// other storage items are untouched.
+			assert_eq!(Value::get(), (1, 2));
+
+			// the map can be used again.
+			NumberMap::insert(key(3), 3);
+			NumberMap::insert(key(4), 4);
+			assert_eq!(
+				NumberMap::enumerate().map(|(_, v)| v).collect::<Vec<_>>(),
+				vec![4, 3],
+			);
+		})
+	}
 }
@@ -26,6 +26,7 @@ pub mod child;
 #[doc(hidden)]
 pub mod generator;
 pub mod migration;
+pub mod counted_linked_map;
 
 /// A trait for working with macro-generated storage values under the substrate storage API.
 ///
@@ -213,6 +214,9 @@ pub trait StorageLinkedMap<K: FullCodec, V: FullCodec> {
 	/// The type that iterates over all `(key, value)`.
 	type Enumerator: Iterator<Item = (K, V)>;
 
+	/// The type that iterates over all `(key, value)`, removing each element as it is yielded.
+	type Drainer: Iterator<Item = (K, V)>;
+
 	/// Does the value (explicitly) exist in storage?
 	fn contains_key<KeyArg: EncodeLike<K>>(key: KeyArg) -> bool;
 
@@ -240,6 +244,25 @@ pub trait StorageLinkedMap<K: FullCodec, V: FullCodec> {
 	/// Enumerate all elements in the map.
 	fn enumerate() -> Self::Enumerator;
 
+	/// Enumerate the elements of the map that come strictly after `key`.
+	///
+	/// This allows resuming an enumeration from a cursor key saved earlier. If `key` is no longer
+	/// in the map, enumeration starts from the current head instead.
+	fn enumerate_from<KeyArg: EncodeLike<K>>(key: KeyArg) -> Self::Enumerator;
+
+	/// Remove all elements from the map, yielding them in enumeration order.
+	///
+	/// Each element is removed from storage when it is yielded, so dropping the iterator early
+	/// leaves the remaining elements in a consistent map. The map may be modified while draining:
+	/// elements inserted in the meantime are drained as well.
+	fn drain() -> Self::Drainer;
+
+	/// Remove all elements from the map.
+	///
+	/// Unlike `drain`, this clears all the values at once using their common storage prefix and
+	/// doesn't rewrite any linkage.
+	fn remove_all();
+
 	/// Read the length of the value in a fast way, without decoding the entire value.
 	///
 	/// `T` is required to implement `Codec::DecodeLength`.
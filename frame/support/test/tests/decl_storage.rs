@@ -638,6 +638,53 @@ mod test_append_and_len {
 		});
 	}
 
+	#[test]
+	fn append_works_for_empty_map_value() {
+		TestExternalities::default().execute_with(|| {
+			assert!(!MapVec::contains_key(1));
+			assert!(MapVec::append(1, [1].iter()).is_ok());
+			assert_eq!(MapVec::get(1), vec![1]);
+
+			assert_eq!(OptionMapVec::get(1), None);
+			assert!(OptionMapVec::append(1, [1].iter()).is_ok());
+			assert_eq!(OptionMapVec::get(1), Some(vec![1]));
+		});
+	}
+
+	#[test]
+	fn append_handles_corrupt_map_value() {
+		use frame_support::storage::unhashed;
+
+		TestExternalities::default().execute_with(|| {
+			// The compact length prefix announces more bytes than are present.
+			unhashed::put_raw(&MapVec::hashed_key_for(1), &[0xff]);
+
+			assert!(MapVec::append(1, [1].iter()).is_err());
+			assert_eq!(unhashed::get_raw(&MapVec::hashed_key_for(1)), Some(vec![0xff]));
+
+			MapVec::append_or_insert(1, &[1, 2][..]);
+			assert_eq!(MapVec::get(1), vec![1, 2]);
+		});
+	}
+
+	#[test]
+	fn append_works_when_length_prefix_grows() {
+		TestExternalities::default().execute_with(|| {
+			// 63 is the largest length encoded in a single compact byte.
+			MapVec::insert(1, &(0..63).collect::<Vec<u32>>());
+
+			assert!(MapVec::append(1, [63].iter()).is_ok());
+			assert_eq!(MapVec::get(1), (0..64).collect::<Vec<u32>>());
+			assert_eq!(MapVec::decode_len(1), Ok(64));
+
+			// 16383 is the largest length encoded in two compact bytes.
+			MapVec::insert(2, &(0..16383).collect::<Vec<u32>>());
+			assert!(MapVec::append(2, [16383, 16384].iter()).is_ok());
+			assert_eq!(MapVec::get(2), (0..16385).collect::<Vec<u32>>());
+			assert_eq!(MapVec::decode_len(2), Ok(16385));
+		});
+	}
+
 	#[test]
 	fn len_works() {
 		TestExternalities::default().execute_with(|| {